@@ -1,7 +1,6 @@
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use js_sys::{Array, Uint8Array, Error};
-use failure::{bail};
 
 #[wasm_bindgen(raw_module = "../../lib/Bork")] // This is  in./bindings/pkg-*/
 extern "C" {
@@ -54,6 +53,15 @@ pub fn magic_num() -> Vec<u8> {
     MAGIC.to_vec()
 }
 
+/// Converts a library error into a JS `Error` whose `code` property is the
+/// stable `borker_rs::Error::code` of its cause.
+fn to_js_error(err: borker_rs::Error) -> JsValue {
+    let js_err = Error::new(&err.to_string());
+    js_sys::Reflect::set(&js_err, &"code".into(), &err.code().into())
+        .expect("setting a property on an Error cannot fail");
+    js_err.into()
+}
+
 fn bytes_to_array(bytes: &[u8]) -> Uint8Array {
     let tmp = unsafe { Uint8Array::view(bytes) };
     tmp.slice(0, bytes.len() as u32)
@@ -61,35 +69,42 @@ fn bytes_to_array(bytes: &[u8]) -> Uint8Array {
 
 #[wasm_bindgen]
 pub fn encode(bork: Bork, parts: Array) -> Result<(), JsValue> {
-    use borker_rs::protocol::{encode, NewBork};
+    use borker_rs::protocol::{encode, BorkMessage};
 
     let encoder = || {
         if let Some(bork) = bork.dyn_ref::<Comment>() {
-            return encode(NewBork::Comment {
-                content: bork.content(),
+            return encode(&BorkMessage::Comment {
+                nonce: bork.nonce(),
                 reference_id: bork.reference(),
-            }, bork.nonce());
+                content: bork.content(),
+            });
         }
         if let Some(bork) = bork.dyn_ref::<Rebork>() {
-            return encode(NewBork::Rebork {
-                content: bork.content(),
+            return encode(&BorkMessage::Rebork {
+                nonce: bork.nonce(),
                 reference_id: bork.reference(),
-            }, bork.nonce());
+                content: bork.content(),
+            });
         }
-        if let Some(_) = bork.dyn_ref::<Extension>() {
-            bail!("can not encode extension borks yet");
+        if let Some(bork) = bork.dyn_ref::<Extension>() {
+            return encode(&BorkMessage::Extension {
+                nonce: bork.nonce(),
+                index: bork.index(),
+                content: bork.content(),
+            });
         }
         if let Some(bork) = bork.dyn_ref::<StandardBork>() {
-            return encode(NewBork::Bork {
+            return encode(&BorkMessage::Bork {
+                nonce: bork.nonce(),
                 content: bork.content(),
-            }, bork.nonce());
+            });
         }
-        bail!("unsupported bork type: {:?}", *bork);
+        Err(borker_rs::Error::Encode(format!("unsupported bork type: {:?}", *bork)))
     };
 
     let encoded = match encoder() {
         Ok(e) => e,
-        Err(msg) => return Err(Error::new(&msg.to_string()).into()),
+        Err(err) => return Err(to_js_error(err)),
     };
 
     for bytes in encoded {
@@ -101,37 +116,40 @@ pub fn encode(bork: Bork, parts: Array) -> Result<(), JsValue> {
 
 #[wasm_bindgen]
 pub fn decode_block(bytes: &[u8], network: usize, borks: Array) -> Result<(), JsValue> {
-    use borker_rs::{process_block, Network, BlockData};
-    use borker_rs::protocol::BorkType;
+    use borker_rs::{process_block, protocol::NoPrevouts, Network, BlockData};
+    use borker_rs::protocol::BorkMessage;
 
     let network = match network {
         1 => Network::Dogecoin,
         2 => Network::Litecoin,
         3 => Network::Bitcoin,
-        _ => return Err(Error::new(&format!("network {} is undefined", network)).into()),
+        _ => {
+            let msg = format!("network {} is undefined", network);
+            return Err(to_js_error(borker_rs::Error::Network(msg)));
+        }
     };
 
     let process = |data: &BlockData| {
         for tx in &data.borker_txs {
-            match tx.bork_type {
-                BorkType::Bork => borks.push(&StandardBork::new(
-                    tx.content.as_ref().unwrap(),
-                    tx.nonce.unwrap(),
+            match &tx.message {
+                BorkMessage::Bork { nonce, content } => borks.push(&StandardBork::new(
+                    content,
+                    *nonce,
                 ).into()),
-                BorkType::Comment => borks.push(&Comment::new(
-                    tx.content.as_ref().unwrap(),
-                    tx.nonce.unwrap(),
-                    tx.reference_id.as_ref().unwrap().as_bytes(),
+                BorkMessage::Comment { nonce, reference_id, content } => borks.push(&Comment::new(
+                    content,
+                    *nonce,
+                    reference_id,
                 ).into()),
-                BorkType::Rebork => borks.push(&Rebork::new(
-                    tx.content.as_ref().unwrap(),
-                    tx.nonce.unwrap(),
-                    tx.reference_id.as_ref().unwrap().as_bytes(),
+                BorkMessage::Rebork { nonce, reference_id, content } => borks.push(&Rebork::new(
+                    content,
+                    *nonce,
+                    reference_id,
                 ).into()),
-                BorkType::Extension => borks.push(&Extension::new(
-                    tx.content.as_ref().unwrap(),
-                    tx.nonce.unwrap(),
-                    tx.index.unwrap(),
+                BorkMessage::Extension { nonce, index, content } => borks.push(&Extension::new(
+                    content,
+                    *nonce,
+                    *index,
                 ).into()),
                 _ => 0,
             };
@@ -139,8 +157,8 @@ pub fn decode_block(bytes: &[u8], network: usize, borks: Array) -> Result<(), Js
         Ok(())
     };
 
-    match process_block(bytes, network, process) {
+    match process_block(bytes, network, &mut NoPrevouts, process) {
         Ok(_) => Ok(()),
-        Err(err) => Err(Error::new(&err.to_string()).into()),
+        Err(err) => Err(to_js_error(err)),
     }
 }
@@ -1,11 +1,8 @@
 use std::fmt;
 use std::marker::PhantomData;
-use serde::ser::{Serialize, Serializer, SerializeTuple};
 use serde::de::{Deserialize, Deserializer, Visitor, SeqAccess, Error};
 
 pub trait BigArray<'de>: Sized {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where S: Serializer;
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where D: Deserializer<'de>;
 }
@@ -14,18 +11,8 @@ macro_rules! big_array {
     ($($len:expr,)+) => {
         $(
             impl<'de, T> BigArray<'de> for [T; $len]
-                where T: Default + Copy + Serialize + Deserialize<'de>
+                where T: Default + Copy + Deserialize<'de>
             {
-                fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-                    where S: Serializer
-                {
-                    let mut seq = serializer.serialize_tuple(self.len())?;
-                    for elem in &self[..] {
-                        seq.serialize_element(elem)?;
-                    }
-                    seq.end()
-                }
-
                 fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
                     where D: Deserializer<'de>
                 {
@@ -60,23 +47,8 @@ macro_rules! big_array {
             }
 
             impl<'de, T> BigArray<'de> for Option<[T; $len]>
-                where T: Default + Copy + Serialize + Deserialize<'de>
+                where T: Default + Copy + Deserialize<'de>
             {
-                fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-                    where S: Serializer
-                {
-                    match self {
-                        Some(a) => {
-                            let mut seq = serializer.serialize_tuple(a.len())?;
-                            for elem in &a[..] {
-                                seq.serialize_element(elem)?;
-                            }
-                            seq.end()
-                        },
-                        None => serializer.serialize_none(),
-                    }
-                }
-
                 fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
                     where D: Deserializer<'de>
                 {
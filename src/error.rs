@@ -0,0 +1,106 @@
+use std::fmt::{self, Debug};
+
+/// Every error returned by this crate, grouped by cause. The message is the
+/// detail; match on the variant (or its `code`) to handle a cause.
+#[derive(Debug)]
+pub enum Error {
+    /// Malformed input: transactions, bork payloads, keys or wallet files.
+    Decode(String),
+    /// A bork or transaction that cannot be built as requested.
+    Encode(String),
+    /// Key derivation or signing failed, or the wallet cannot do what was
+    /// asked of it.
+    Wallet(String),
+    /// An address that is invalid or not of a supported type.
+    Address(String),
+    /// The inputs cannot pay for the outputs and fee.
+    Funds(String),
+    /// Data meant for another network, or a feature the network lacks.
+    Network(String),
+    /// BIP32 derivation at this index produced an invalid key. Callers
+    /// should move on to the next index.
+    InvalidChild(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Decode(s)
+            | Error::Encode(s)
+            | Error::Wallet(s)
+            | Error::Address(s)
+            | Error::Funds(s)
+            | Error::Network(s) => write!(f, "{}", s),
+            Error::InvalidChild(i) => write!(f, "child {} is an invalid BIP32 index", i),
+        }
+    }
+}
+
+impl failure::Fail for Error {}
+
+impl Error {
+    /// A stable name for the variant, for bindings that cannot match on it.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Decode(_) => "DECODE",
+            Error::Encode(_) => "ENCODE",
+            Error::Wallet(_) => "WALLET",
+            Error::Address(_) => "ADDRESS",
+            Error::Funds(_) => "FUNDS",
+            Error::Network(_) => "NETWORK",
+            Error::InvalidChild(_) => "INVALID_CHILD",
+        }
+    }
+}
+
+fn debug(e: impl Debug) -> String {
+    format!("{:?}", e)
+}
+
+impl From<bitcoin::consensus::encode::Error> for Error {
+    fn from(e: bitcoin::consensus::encode::Error) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<hex::FromHexError> for Error {
+    fn from(e: hex::FromHexError) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<serde_cbor::error::Error> for Error {
+    fn from(e: serde_cbor::error::Error) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<bitcoin_bech32::Error> for Error {
+    fn from(e: bitcoin_bech32::Error) -> Self {
+        Error::Address(debug(e))
+    }
+}
+
+impl From<secp256k1::Error> for Error {
+    fn from(e: secp256k1::Error) -> Self {
+        Error::Wallet(debug(e))
+    }
+}
+
+impl From<bitcoin::util::base58::Error> for Error {
+    fn from(e: bitcoin::util::base58::Error) -> Self {
+        Error::Decode(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Encode(e.to_string())
+    }
+}
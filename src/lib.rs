@@ -1,23 +1,29 @@
 #![feature(slice_concat_ext)]
 
-#[macro_use]
-extern crate failure;
-
-use failure::Error;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 mod big_array;
+mod error;
 #[macro_use]
 mod macros;
 pub mod protocol;
 mod wallet;
 
-pub use self::wallet::{ChildWallet, Wallet};
+pub use self::error::Error;
+pub use self::wallet::{
+    check_author, coin_type, complete_sponsored, construct_authored, construct_sponsor_request,
+    decrypt_direct_message, discover_account, discover_chain, encrypt_direct_message, extract_psbt,
+    finalize_psbt, from_wif, reassembly_window, recover_message_signer, reservation_timeout,
+    sign_message, sweep, to_wif, verify_message, AccountUsage, AddressHistory, Bip44Path, Chain,
+    ChildNumber, ChildWallet, DerivationPath, DiscoveryOptions, KeyWallet, NonceAllocator,
+    OpenNonce, PathInputs, Signer, Wallet, SPONSORED_SIGHASH,
+};
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-pub struct BlockData<'a> {
-    pub borker_txs: Vec<protocol::BorkTxData<'a>>,
+pub struct BlockData {
+    pub borker_txs: Vec<protocol::BorkTx>,
     pub spent: Vec<protocol::UtxoId>,
     pub created: Vec<protocol::NewUtxo>,
 }
@@ -41,7 +47,99 @@ pub enum Network {
     Bitcoin,
 }
 
-pub fn process_block<T>(block: &[u8], network: Network, process: impl FnOnce(&BlockData) -> Result<T, Error>) -> Result<T, Error> {
+/// Decodes every bork in `block` and lists the outputs it spends and
+/// creates. Segwit authors are checked against the outputs they spend, as
+/// found in `prevouts` or earlier in the block.
+pub fn process_block<T, P: protocol::PrevoutSource>(
+    block: &[u8],
+    network: Network,
+    prevouts: &mut P,
+    process: impl FnOnce(&BlockData) -> Result<T, Error>,
+) -> Result<T, Error> {
+    use bitcoin::BitcoinHash;
+
+    let (header, txs) = read_block(block, network)?;
+    let hash = format!("{:x}", header.bitcoin_hash());
+    let height = txs
+        .first()
+        .and_then(|coinbase| bip34_height(&header, coinbase, network));
+    let mut block_data = BlockData {
+        borker_txs: Vec::new(),
+        spent: Vec::new(),
+        created: Vec::new(),
+    };
+    let mut block_outputs = BlockOutputs::new(prevouts);
+    for tx in txs {
+        block_outputs.add(&tx);
+        let (bork, spent, created) =
+            protocol::parse_tx(tx, block_time(&header), network, &mut block_outputs);
+        if let Some(mut bork) = bork {
+            bork.block_hash = Some(hash.clone());
+            bork.block_height = height;
+            block_data.borker_txs.push(bork);
+        }
+        block_data.spent.extend(spent);
+        block_data.created.extend(created);
+    }
+    process(&block_data)
+}
+
+/// Lists the transactions in `block` that carry bork data but were dropped
+/// by `process_block`, with the reason each was rejected.
+pub fn diagnose_block<P: protocol::PrevoutSource>(
+    block: &[u8],
+    network: Network,
+    prevouts: &mut P,
+) -> Result<Vec<protocol::Rejection>, Error> {
+    let (header, txs) = read_block(block, network)?;
+    let mut block_outputs = BlockOutputs::new(prevouts);
+    let mut res = Vec::new();
+    for tx in &txs {
+        block_outputs.add(tx);
+        let time = block_time(&header);
+        if let Some(Err(rejection)) = protocol::diagnose_tx(tx, time, network, &mut block_outputs) {
+            res.push(rejection);
+        }
+    }
+    Ok(res)
+}
+
+/// The outputs of the transactions seen so far in a block, which later ones
+/// may spend, in front of the caller's `PrevoutSource`.
+struct BlockOutputs<'a, P> {
+    outputs: HashMap<bitcoin::OutPoint, bitcoin::TxOut>,
+    prevouts: &'a mut P,
+}
+impl<'a, P: protocol::PrevoutSource> BlockOutputs<'a, P> {
+    fn new(prevouts: &'a mut P) -> Self {
+        BlockOutputs {
+            outputs: HashMap::new(),
+            prevouts,
+        }
+    }
+
+    fn add(&mut self, tx: &bitcoin::Transaction) {
+        let txid = tx.txid();
+        for (vout, o) in tx.output.iter().enumerate() {
+            let vout = vout as u32;
+            self.outputs
+                .insert(bitcoin::OutPoint { txid, vout }, o.clone());
+        }
+    }
+}
+impl<'a, P: protocol::PrevoutSource> protocol::PrevoutSource for BlockOutputs<'a, P> {
+    fn prevout(&mut self, outpoint: &bitcoin::OutPoint) -> Option<bitcoin::TxOut> {
+        match self.outputs.get(outpoint) {
+            Some(o) => Some(o.clone()),
+            None => self.prevouts.prevout(outpoint),
+        }
+    }
+}
+
+fn read_block(
+    block: &[u8],
+    network: Network,
+) -> Result<(bitcoin::BlockHeader, Vec<bitcoin::Transaction>), Error> {
     use bitcoin::consensus::encode::Decodable;
 
     let mut cur = std::io::Cursor::new(&block);
@@ -64,26 +162,41 @@ pub fn process_block<T>(block: &[u8], network: Network, process: impl FnOnce(&Bl
     }
 
     let count: bitcoin::VarInt = Decodable::consensus_decode(&mut cur)?;
-    let timestamp = chrono::DateTime::from_utc(
-        chrono::NaiveDateTime::from_timestamp(block_header.time as i64, 0),
+    let mut txs = Vec::new();
+    for _ in 0..count.0 {
+        txs.push(Decodable::consensus_decode(&mut cur)?);
+    }
+    Ok((block_header, txs))
+}
+
+fn block_time(header: &bitcoin::BlockHeader) -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::from_utc(
+        chrono::NaiveDateTime::from_timestamp(header.time as i64, 0),
         chrono::Utc,
-    );
-    let mut block_data = BlockData {
-        borker_txs: Vec::new(),
-        spent: Vec::new(),
-        created: Vec::new(),
+    )
+}
+
+/// The height committed to by the coinbase of a block new enough to follow
+/// BIP34. Dogecoin keeps its chain ID and AuxPoW flag above the low byte of
+/// the version.
+fn bip34_height(
+    header: &bitcoin::BlockHeader,
+    coinbase: &bitcoin::Transaction,
+    network: Network,
+) -> Option<u32> {
+    use bitcoin::blockdata::script::Instruction;
+
+    let version = match network {
+        Network::Dogecoin => header.version & 0xff,
+        _ => header.version,
     };
-    for _ in 0..count.0 {
-        let (bork, spent, created) = protocol::parse_tx(
-            Decodable::consensus_decode(&mut cur)?,
-            &timestamp,
-            network,
-        );
-        if let Some(bork) = bork {
-            block_data.borker_txs.push(bork);
+    if version < 2 {
+        return None;
+    }
+    match coinbase.input.first()?.script_sig.iter(true).next()? {
+        Instruction::PushBytes(b) if !b.is_empty() && b.len() <= 4 => {
+            Some(b.iter().rev().fold(0, |acc, byte| acc << 8 | *byte as u32))
         }
-        block_data.spent.extend(spent);
-        block_data.created.extend(created);
+        _ => None,
     }
-    process(&block_data)
 }
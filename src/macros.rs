@@ -11,3 +11,23 @@ macro_rules! mask_16 {
         2_u16.pow($x) - 1
     };
 }
+
+macro_rules! err {
+    ($kind:ident, $($arg:tt)+) => {
+        crate::Error::$kind(format!($($arg)+))
+    };
+}
+
+macro_rules! bail {
+    ($kind:ident, $($arg:tt)+) => {
+        return Err(err!($kind, $($arg)+))
+    };
+}
+
+macro_rules! ensure {
+    ($cond:expr, $kind:ident, $($arg:tt)+) => {
+        if !$cond {
+            bail!($kind, $($arg)+);
+        }
+    };
+}
@@ -1,17 +1,40 @@
 
+use crate::Error;
 use crate::Network;
-use failure::Error;
-use std::io::Write;
 
 use crate::wallet::addr_to_script;
+use crate::wallet::hash160;
 use crate::wallet::is_p2pkh;
+use crate::wallet::p2wpkh_script;
 use crate::wallet::pubkey_hash_to_addr;
 use crate::wallet::pubkey_to_addr;
+use crate::wallet::redeem_script_to_addr;
+use crate::wallet::script_pubkey_hash;
+use crate::wallet::script_to_addr;
 use serde::{Deserialize, Serialize};
 use chrono::DateTime;
 use chrono::Utc;
+use secp256k1::PublicKeyFormat;
+use std::collections::HashMap;
 
-pub const MAGIC: [u8; 2] = [0xD0, 0x6E];
+/// The first byte of every message.
+pub const PROTOCOL_MARKER: u8 = 0xD0;
+/// The protocol version written by `encode`, and the only one whose message
+/// types this crate understands.
+pub const PROTOCOL_VERSION: u8 = 0x6E;
+/// The bytes that begin every message `encode` writes.
+pub const MAGIC: [u8; 2] = [PROTOCOL_MARKER, PROTOCOL_VERSION];
+/// The highest message type `PROTOCOL_VERSION` defines, the direct message
+/// extension. Higher types decode as `BorkMessage::Unknown`.
+pub const LAST_KNOWN_TYPE: u8 = 0x11;
+
+/// The most data that fits in one OP_RETURN output.
+pub const MAX_PART_LEN: usize = 80;
+/// The most transactions one message can span: the first and 255
+/// extensions, numbered by a one-byte index.
+pub const MAX_PARTS: usize = 256;
+/// The longest content `encode` accepts, which always fits in `MAX_PARTS`.
+pub const MAX_CONTENT_LEN: usize = 16384;
 
 #[derive(Debug, Serialize)]
 pub struct UtxoId {
@@ -29,52 +52,117 @@ pub struct NewUtxo {
     pub raw: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// How the author signed input 0.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
-pub enum BorkType {
-    SetName,
-    SetBio,
-    SetAvatar,
-    Bork,
-    Comment,
-    Rebork,
-    Extension,
-    Delete,
-    Like,
-    Unlike,
-    Flag,
-    Unflag,
-    Follow,
-    Unfollow,
-    Block,
-    Unblock,
+pub enum AuthorKind {
+    PubkeyHash,
+    Multisig,
+    WitnessPubkeyHash,
 }
 
-#[derive(Debug, Serialize)]
+/// Source of the outputs spent by transactions, such as an indexer's UTXO
+/// set. Segwit signatures commit to the amount spent, which is not part of
+/// the spending transaction, so a segwit author is only credited when the
+/// output it spends can be looked up.
+pub trait PrevoutSource {
+    fn prevout(&mut self, outpoint: &bitcoin::OutPoint) -> Option<bitcoin::TxOut>;
+}
+impl<F> PrevoutSource for F
+where
+    F: FnMut(&bitcoin::OutPoint) -> Option<bitcoin::TxOut>,
+{
+    fn prevout(&mut self, outpoint: &bitcoin::OutPoint) -> Option<bitcoin::TxOut> {
+        self(outpoint)
+    }
+}
+impl PrevoutSource for HashMap<bitcoin::OutPoint, bitcoin::TxOut> {
+    fn prevout(&mut self, outpoint: &bitcoin::OutPoint) -> Option<bitcoin::TxOut> {
+        self.get(outpoint).cloned()
+    }
+}
+
+/// Knows no outputs, so segwit spends have no author.
+pub struct NoPrevouts;
+impl PrevoutSource for NoPrevouts {
+    fn prevout(&mut self, _: &bitcoin::OutPoint) -> Option<bitcoin::TxOut> {
+        None
+    }
+}
+
+/// A decoded bork. The block fields are set by `process_block`, and are
+/// `None` for borks decoded from loose transactions.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub struct BorkTxData<'a> {
-    pub time: &'a DateTime<Utc>,
+pub struct BorkTx {
+    pub time: DateTime<Utc>,
     pub txid: String,
-    #[serde(rename = "type")]
-    pub bork_type: BorkType,
-    pub nonce: Option<u8>,
-    pub index: Option<u8>,
-    pub reference_id: Option<String>,
-    pub content: Option<String>,
+    /// The OP_RETURN output holding the message.
+    pub output_index: u32,
+    pub block_hash: Option<String>,
+    pub block_height: Option<u32>,
+    #[serde(flatten)]
+    pub message: BorkMessage,
     pub sender_address: String,
+    pub author_kind: AuthorKind,
     pub recipient_address: Option<String>,
     pub mentions: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct NewBorkData {
-    #[serde(rename = "type")]
-    pub bork_type: BorkType,
-    pub content: Option<String>,
-    pub reference_id: Option<String>,
+/// A transaction carrying bork data that did not decode.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rejection {
+    pub txid: String,
+    pub reason: RejectReason,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "camelCase")]
+pub enum RejectReason {
+    /// Input 0 is not a P2PKH, P2SH multisig or P2WPKH spend that authors
+    /// can be recovered from.
+    UnsupportedInput,
+    /// Input 0 is a P2WPKH spend of an output the `PrevoutSource` does not
+    /// know, so its signature cannot be checked.
+    MissingPrevout,
+    /// Input 0 lacks valid signatures for the keys it reveals.
+    BadSignature,
+    /// The payload ends before the message does, at the given byte.
+    Truncated(usize),
+    /// The content is not valid UTF-8 after the given number of bytes.
+    InvalidUtf8(usize),
+    /// The message needs a recipient, but no output pays anyone other than
+    /// the author and the keys spending the inputs.
+    MissingRecipient,
+    /// The transaction has this many OP_RETURN outputs rather than one.
+    MultipleOpReturns(usize),
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RejectReason::UnsupportedInput => write!(f, "input 0 has no recoverable author"),
+            RejectReason::MissingPrevout => write!(f, "output spent by input 0 is unknown"),
+            RejectReason::BadSignature => write!(f, "input 0 has no valid author signature"),
+            RejectReason::Truncated(at) => write!(f, "unexpected end of input at byte {}", at),
+            RejectReason::InvalidUtf8(at) => {
+                write!(f, "content is not valid UTF-8 after byte {}", at)
+            }
+            RejectReason::MissingRecipient => write!(f, "missing recipient output"),
+            RejectReason::MultipleOpReturns(n) => {
+                write!(f, "{} OP_RETURN outputs, only one is allowed", n)
+            }
+        }
+    }
 }
 
-pub enum NewBork {
+/// One protocol message, as carried by a single OP_RETURN output. For any
+/// message that fits in one part, `decode(&encode(&m)?[0], network)? == m`.
+/// Content too long for one part continues in `Extension` messages.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BorkMessage {
     SetName {
         content: String,
     },
@@ -85,324 +173,419 @@ pub enum NewBork {
         content: String,
     },
     Bork {
+        nonce: u8,
         content: String,
     },
+    #[serde(rename_all = "camelCase")]
     Comment {
+        nonce: u8,
+        #[serde(with = "hex_bytes")]
         reference_id: Vec<u8>,
         content: String,
     },
+    #[serde(rename_all = "camelCase")]
     Rebork {
+        nonce: u8,
+        #[serde(with = "hex_bytes")]
         reference_id: Vec<u8>,
         content: String,
     },
+    Extension {
+        nonce: u8,
+        index: u8,
+        content: String,
+    },
+    #[serde(rename_all = "camelCase")]
     Delete {
+        #[serde(with = "hex_bytes")]
         reference_id: Vec<u8>,
     },
+    #[serde(rename_all = "camelCase")]
     Like {
+        #[serde(with = "hex_bytes")]
         reference_id: Vec<u8>,
     },
+    #[serde(rename_all = "camelCase")]
     Unlike {
-        txid: Vec<u8>,
+        #[serde(with = "hex_bytes")]
+        reference_txid: Vec<u8>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Flag {
+        #[serde(with = "hex_bytes")]
+        reference_txid: Vec<u8>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Unflag {
+        #[serde(with = "hex_bytes")]
+        reference_txid: Vec<u8>,
     },
     Follow {
-        address: Vec<u8>,
+        address: String,
     },
     Unfollow {
-        address: Vec<u8>,
+        address: String,
     },
     Block {
-        address: Vec<u8>,
+        address: String,
     },
     Unblock {
-        address: Vec<u8>,
+        address: String,
     },
-    Flag {
-        txid: Vec<u8>,
+    /// Ciphertext from `encrypt_direct_message`, readable only by the sender
+    /// and the owner of the first recipient output.
+    DirectMessage {
+        nonce: u8,
+        #[serde(with = "hex_bytes")]
+        ciphertext: Vec<u8>,
     },
-    Unflag {
-        txid: Vec<u8>,
+    /// Continues the ciphertext of a `DirectMessage`, like `Extension`.
+    DirectMessageExtension {
+        nonce: u8,
+        index: u8,
+        #[serde(with = "hex_bytes")]
+        ciphertext: Vec<u8>,
+    },
+    /// A well-formed message of a version or type this crate does not know,
+    /// kept as is so it can be indexed now and understood later.
+    #[serde(rename_all = "camelCase")]
+    Unknown {
+        version: u8,
+        message_type: u8,
+        #[serde(with = "hex_bytes")]
+        payload: Vec<u8>,
     },
 }
 
-impl std::convert::TryFrom<NewBorkData> for NewBork {
-    type Error = Error;
+/// Writes byte fields as hex strings.
+mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
 
-    fn try_from(data: NewBorkData) -> Result<Self, Self::Error> {
-        match data.bork_type {
-            BorkType::Extension => bail!("cannot directly generate an extension"),
-            BorkType::SetName => {
-                let content = data.content.ok_or(format_err!("missing content"))?;
-                if content.as_bytes().len() > 77 {
-                    bail!("content exceeds maximum length");
-                }
-                Ok(NewBork::SetName { content })
-            }
-            BorkType::SetBio => {
-                let content = data.content.ok_or(format_err!("missing content"))?;
-                if content.as_bytes().len() > 77 {
-                    bail!("content exceeds maximum length");
-                }
-                Ok(NewBork::SetBio { content })
-            }
-            BorkType::SetAvatar => {
-                let content = data.content.ok_or(format_err!("missing content"))?;
-                if content.as_bytes().len() > 77 {
-                    bail!("content exceeds maximum length");
-                }
-                Ok(NewBork::SetAvatar { content })
-            }
-            BorkType::Bork => Ok(NewBork::Bork {
-                content: data.content.ok_or(format_err!("missing content"))?,
-            }),
-            BorkType::Comment => {
-                let reference_id = hex::decode(
-                    &data
-                        .reference_id
-                        .ok_or(format_err!("missing reference_id"))?,
-                )?;
-                if reference_id.len() > 32 {
-                    bail!("reference_id exceeds maximum length");
-                }
-                Ok(NewBork::Comment {
-                    content: data.content.ok_or(format_err!("missing content"))?,
-                    reference_id,
-                })
-            }
-            BorkType::Rebork => {
-                let reference_id = hex::decode(
-                    &data
-                        .reference_id
-                        .ok_or(format_err!("missing reference_id"))?,
-                )?;
-                if reference_id.len() > 32 {
-                    bail!("reference_id exceeds maximum length");
-                }
-                Ok(NewBork::Rebork {
-                    content: data.content.ok_or(format_err!("missing content"))?,
-                    reference_id,
-                })
-            }
-            BorkType::Delete => {
-                let reference_id = hex::decode(
-                    &data
-                        .reference_id
-                        .ok_or(format_err!("missing reference_id"))?,
-                )?;
-                if reference_id.len() > 32 {
-                    bail!("reference_id exceeds maximum length");
-                }
-                Ok(NewBork::Delete { reference_id })
-            }
-            BorkType::Like => {
-                let reference_id = hex::decode(
-                    &data
-                        .reference_id
-                        .ok_or(format_err!("missing reference_id"))?,
-                )?;
-                if reference_id.len() > 32 {
-                    bail!("reference_id exceeds maximum length");
-                }
-                Ok(NewBork::Like { reference_id })
-            }
-            BorkType::Unlike => {
-                let txid = hex::decode(&data.reference_id.ok_or(format_err!("missing txid"))?)?;
-                if txid.len() != 32 {
-                    bail!("invalid length for txid");
-                }
-                Ok(NewBork::Unlike { txid })
-            }
-            BorkType::Flag => {
-                let txid = hex::decode(&data.reference_id.ok_or(format_err!("missing txid"))?)?;
-                if txid.len() != 32 {
-                    bail!("invalid length for txid");
-                }
-                Ok(NewBork::Flag { txid })
-            }
-            BorkType::Unflag => {
-                let txid = hex::decode(&data.reference_id.ok_or(format_err!("missing txid"))?)?;
-                if txid.len() != 32 {
-                    bail!("invalid length for txid");
-                }
-                Ok(NewBork::Unflag { txid })
-            }
-            BorkType::Follow => {
-                let mut address = bitcoin::util::base58::from_check(
-                    &data.content.ok_or(format_err!("missing content"))?,
-                )?;
-                if !is_p2pkh(address.remove(0)) {
-                    bail!("address is not P2PKH");
-                }
-                Ok(NewBork::Follow { address })
-            }
-            BorkType::Unfollow => {
-                let mut address = bitcoin::util::base58::from_check(
-                    &data.content.ok_or(format_err!("missing content"))?,
-                )?;
-                if !is_p2pkh(address.remove(0)) {
-                    bail!("address is not P2PKH");
-                }
-                Ok(NewBork::Unfollow { address })
-            }
-            BorkType::Block => {
-                let mut address = bitcoin::util::base58::from_check(
-                    &data.content.ok_or(format_err!("missing content"))?,
-                )?;
-                if !is_p2pkh(address.remove(0)) {
-                    bail!("address is not P2PKH");
-                }
-                Ok(NewBork::Block { address })
-            }
-            BorkType::Unblock => {
-                let mut address = bitcoin::util::base58::from_check(
-                    &data.content.ok_or(format_err!("missing content"))?,
-                )?;
-                if !is_p2pkh(address.remove(0)) {
-                    bail!("address is not P2PKH");
-                }
-                Ok(NewBork::Unblock { address })
-            }
-        }
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        hex::decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
     }
 }
 
-pub fn encode(bork: NewBork, nonce: u8) -> Result<Vec<Vec<u8>>, Error> {
-    let mut buf_vec: Vec<Vec<u8>> = Vec::new();
+/// Encodes `message` as OP_RETURN data, one `Vec` per transaction. The
+/// content of a bork, comment or rebork that does not fit in one part is
+/// split into the `Extension` parts that follow it, and the ciphertext of a
+/// direct message into `DirectMessageExtension` parts.
+pub fn encode(message: &BorkMessage) -> Result<Vec<Vec<u8>>, Error> {
+    let version = match message {
+        BorkMessage::Unknown { version, .. } => *version,
+        _ => PROTOCOL_VERSION,
+    };
+    encode_version(message, version)
+}
+
+/// Like `encode`, but for protocol `version`. Known message types can only be
+/// written for `PROTOCOL_VERSION`; an `Unknown` message only for its own.
+pub fn encode_version(message: &BorkMessage, version: u8) -> Result<Vec<Vec<u8>>, Error> {
+    match message {
+        BorkMessage::Unknown { version: v, .. } => ensure!(
+            *v == version,
+            Encode,
+            "message is for protocol version {:#04x}, not {:#04x}",
+            v,
+            version
+        ),
+        _ => ensure!(
+            version == PROTOCOL_VERSION,
+            Encode,
+            "protocol version {:#04x} is not supported",
+            version
+        ),
+    }
     let mut buf: Vec<u8> = Vec::new();
-    buf.push(MAGIC[0]);
-    buf.push(MAGIC[1]);
-    let content: Option<Vec<u8>> = match bork {
-        NewBork::SetName { content } => {
+    buf.push(PROTOCOL_MARKER);
+    buf.push(version);
+    let continued: Option<Continued> = match message {
+        BorkMessage::SetName { content } => {
             buf.push(0x00);
-            buf.write(content.as_bytes())?;
+            buf.extend(content.as_bytes());
             None
         }
-        NewBork::SetBio { content } => {
+        BorkMessage::SetBio { content } => {
             buf.push(0x01);
-            buf.write(content.as_bytes())?;
+            buf.extend(content.as_bytes());
             None
         }
-        NewBork::SetAvatar { content } => {
+        BorkMessage::SetAvatar { content } => {
             buf.push(0x02);
-            buf.write(content.as_bytes())?;
+            buf.extend(content.as_bytes());
             None
         }
-        NewBork::Bork { content } => {
+        BorkMessage::Bork { nonce, content } => {
             buf.push(0x03);
-            buf.push(nonce);
-            Some(content.into_bytes())
+            buf.push(*nonce);
+            Some(Continued::Text(*nonce, content))
         }
-        NewBork::Comment {
+        BorkMessage::Comment {
+            nonce,
             reference_id,
             content,
         } => {
             buf.push(0x04);
-            buf.push(nonce);
-            buf.push(reference_id.len() as u8);
-            buf.extend(reference_id);
-            Some(content.into_bytes())
+            buf.push(*nonce);
+            push_reference(&mut buf, reference_id)?;
+            Some(Continued::Text(*nonce, content))
         }
-        NewBork::Rebork {
+        BorkMessage::Rebork {
+            nonce,
             reference_id,
             content,
         } => {
             buf.push(0x05);
-            buf.push(nonce);
-            buf.push(reference_id.len() as u8);
-            buf.extend(reference_id);
-            Some(content.into_bytes())
+            buf.push(*nonce);
+            push_reference(&mut buf, reference_id)?;
+            Some(Continued::Text(*nonce, content))
         }
-        NewBork::Delete { reference_id } => {
+        BorkMessage::Extension {
+            nonce,
+            index,
+            content,
+        } => {
+            buf.push(0x06);
+            buf.push(*nonce);
+            buf.push(*index);
+            buf.extend(content.as_bytes());
+            None
+        }
+        BorkMessage::Delete { reference_id } => {
             buf.push(0x07);
-            buf.push(reference_id.len() as u8);
-            buf.extend(reference_id);
+            push_reference(&mut buf, reference_id)?;
             None
         }
-        NewBork::Like { reference_id } => {
+        BorkMessage::Like { reference_id } => {
             buf.push(0x08);
-            buf.push(reference_id.len() as u8);
-            buf.extend(reference_id);
+            push_reference(&mut buf, reference_id)?;
             None
         }
-        NewBork::Unlike { txid } => {
+        BorkMessage::Unlike { reference_txid } => {
             buf.push(0x09);
-            buf.extend(txid);
+            push_txid(&mut buf, reference_txid)?;
             None
         }
-        NewBork::Flag { txid } => {
+        BorkMessage::Flag { reference_txid } => {
             buf.push(0x0A);
-            buf.extend(txid);
+            push_txid(&mut buf, reference_txid)?;
             None
         }
-        NewBork::Unflag { txid } => {
+        BorkMessage::Unflag { reference_txid } => {
             buf.push(0x0B);
-            buf.extend(txid);
+            push_txid(&mut buf, reference_txid)?;
             None
         }
-        NewBork::Follow { address } => {
+        BorkMessage::Follow { address } => {
             buf.push(0x0C);
-            buf.extend(address);
+            buf.extend(address_hash(address)?);
             None
         }
-        NewBork::Unfollow { address } => {
+        BorkMessage::Unfollow { address } => {
             buf.push(0x0D);
-            buf.extend(address);
+            buf.extend(address_hash(address)?);
             None
         }
-        NewBork::Block { address } => {
+        BorkMessage::Block { address } => {
             buf.push(0x0E);
-            buf.extend(address);
+            buf.extend(address_hash(address)?);
             None
         }
-        NewBork::Unblock { address } => {
+        BorkMessage::Unblock { address } => {
             buf.push(0x0F);
-            buf.extend(address);
+            buf.extend(address_hash(address)?);
             None
         }
+        BorkMessage::DirectMessage { nonce, ciphertext } => {
+            buf.push(0x10);
+            buf.push(*nonce);
+            Some(Continued::Binary(*nonce, ciphertext))
+        }
+        BorkMessage::DirectMessageExtension {
+            nonce,
+            index,
+            ciphertext,
+        } => {
+            buf.push(0x11);
+            buf.push(*nonce);
+            buf.push(*index);
+            buf.extend(ciphertext);
+            None
+        }
+        BorkMessage::Unknown {
+            message_type,
+            payload,
+            ..
+        } => {
+            ensure!(
+                version != PROTOCOL_VERSION || *message_type > LAST_KNOWN_TYPE,
+                Encode,
+                "message type {:#04x} is known and must not be sent as unknown",
+                message_type
+            );
+            buf.push(*message_type);
+            buf.extend(payload);
+            None
+        }
+    };
+    let room = MAX_PART_LEN.saturating_sub(buf.len());
+    let (nonce, extension, parts): (u8, u8, Vec<&[u8]>) = match continued {
+        Some(Continued::Text(nonce, content)) => {
+            ensure!(
+                content.len() <= MAX_CONTENT_LEN,
+                Encode,
+                "content is {} bytes, the maximum is {}",
+                content.len(),
+                MAX_CONTENT_LEN
+            );
+            let parts = split_content(content, room);
+            (nonce, 0x06, parts.into_iter().map(str::as_bytes).collect())
+        }
+        Some(Continued::Binary(nonce, data)) => (nonce, 0x11, split_bytes(data, room)),
+        None => {
+            ensure!(
+                buf.len() <= MAX_PART_LEN,
+                Encode,
+                "message is {} bytes, the maximum is {}",
+                buf.len(),
+                MAX_PART_LEN
+            );
+            return Ok(vec![buf]);
+        }
     };
-    if let Some(content) = content {
-        let remaining = (80 - buf.len()).min(content.len());
-        buf.write(&content[..remaining])?;
-        for c in content[remaining..].chunks(75) {
+    ensure!(
+        parts.len() <= MAX_PARTS,
+        Encode,
+        "content needs {} transactions, the maximum is {}",
+        parts.len(),
+        MAX_PARTS
+    );
+    let mut buf_vec: Vec<Vec<u8>> = Vec::new();
+    for (i, part) in parts.into_iter().enumerate() {
+        if i > 0 {
             buf_vec.push(buf);
-            buf = Vec::new();
-            buf.push(MAGIC[0]);
-            buf.push(MAGIC[1]);
-            buf.push(0x06);
-            buf.push(nonce);
-            buf.push(buf_vec.len() as u8);
-            buf.write(&c)?;
+            buf = vec![PROTOCOL_MARKER, version, extension, nonce, i as u8];
         }
-        buf_vec.push(buf);
+        buf.extend(part);
     }
+    buf_vec.push(buf);
 
     Ok(buf_vec)
 }
 
+/// The part of a message that may continue in extensions.
+enum Continued<'a> {
+    Text(u8, &'a str),
+    Binary(u8, &'a [u8]),
+}
+
+fn push_reference(buf: &mut Vec<u8>, reference_id: &[u8]) -> Result<(), Error> {
+    ensure!(
+        reference_id.len() <= 32,
+        Encode,
+        "reference_id exceeds maximum length"
+    );
+    buf.push(reference_id.len() as u8);
+    buf.extend(reference_id);
+    Ok(())
+}
+
+fn push_txid(buf: &mut Vec<u8>, txid: &[u8]) -> Result<(), Error> {
+    ensure!(txid.len() == 32, Encode, "invalid length for txid");
+    buf.extend(txid);
+    Ok(())
+}
+
+/// The pubkey hash of a P2PKH `address`.
+fn address_hash(address: &str) -> Result<Vec<u8>, Error> {
+    let data = bitcoin::util::base58::from_check(address)?;
+    ensure!(
+        data.len() == 21 && is_p2pkh(data[0]),
+        Address,
+        "address is not P2PKH"
+    );
+    Ok(data[1..].to_vec())
+}
+
+/// How much a message will take on chain once encoded.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Cost {
+    /// Transactions needed, one per part.
+    pub transactions: usize,
+    /// Total OP_RETURN data across all parts.
+    pub bytes: usize,
+}
+
+/// Estimates the cost of `message` without building any transactions.
+/// Fails like `encode` if it is too long to send.
+pub fn estimate_cost(message: &BorkMessage) -> Result<Cost, Error> {
+    let parts = encode(message)?;
+    Ok(Cost {
+        transactions: parts.len(),
+        bytes: parts.iter().map(|p| p.len()).sum(),
+    })
+}
+
+/// Splits `content` into parts for a message whose first part has room
+/// for `first` bytes, and each extension for 75.
+fn split_content(content: &str, first: usize) -> Vec<&str> {
+    let (head, mut rest) = split_at_char(content, first);
+    let mut parts = vec![head];
+    while !rest.is_empty() {
+        let (part, tail) = split_at_char(rest, MAX_PART_LEN - 5);
+        parts.push(part);
+        rest = tail;
+    }
+    parts
+}
+
+/// Splits binary `data` into parts like `split_content`.
+fn split_bytes(data: &[u8], first: usize) -> Vec<&[u8]> {
+    let (head, rest) = data.split_at(first.min(data.len()));
+    let mut parts = vec![head];
+    parts.extend(rest.chunks(MAX_PART_LEN - 5));
+    parts
+}
+
+/// Splits `s` at the last char boundary within `max` bytes, so that every
+/// part of a long bork is valid UTF-8 on its own.
+fn split_at_char(s: &str, max: usize) -> (&str, &str) {
+    let mut i = max.min(s.len());
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    s.split_at(i)
+}
+
 struct Cur<'a, T: Clone>(&'a [T], usize);
 impl<'a, T> Cur<'a, T>
 where
     T: Clone,
 {
-    pub fn peek(&self) -> Result<T, Error> {
-        self.0
-            .get(self.1)
-            .map(|a: &T| -> T { a.clone() })
-            .ok_or(format_err!("unexpected end of input"))
+    fn end(&self) -> RejectReason {
+        RejectReason::Truncated(self.1)
     }
 
-    pub fn peek_n(&self, n: usize) -> Result<&'a [T], Error> {
-        self.0
-            .get(self.1..(self.1 + n))
-            .ok_or(format_err!("unexpected end of input"))
+    pub fn peek(&self) -> Result<T, RejectReason> {
+        self.0.get(self.1).cloned().ok_or_else(|| self.end())
     }
 
-    pub fn next(&mut self) -> Result<T, Error> {
+    pub fn peek_n(&self, n: usize) -> Result<&'a [T], RejectReason> {
+        self.0.get(self.1..(self.1 + n)).ok_or_else(|| self.end())
+    }
+
+    pub fn next(&mut self) -> Result<T, RejectReason> {
         let ret = self.peek()?;
         self.1 += 1;
         Ok(ret)
     }
 
-    pub fn next_n(&mut self, n: usize) -> Result<&[T], Error> {
+    pub fn next_n(&mut self, n: usize) -> Result<&[T], RejectReason> {
         let ret = self.peek_n(n)?;
         self.1 += n;
         Ok(ret)
@@ -414,318 +597,481 @@ where
 }
 impl<'a> Cur<'a, u8> {
 
-    pub fn var_peek(&self) -> Result<&'a [u8], Error> {
-        let len = *self
-            .0
-            .get(self.1)
-            .ok_or(format_err!("unexpected end of input"))?;
+    pub fn var_peek(&self) -> Result<&'a [u8], RejectReason> {
+        let len = *self.0.get(self.1).ok_or_else(|| self.end())?;
         self.0
             .get((self.1 + 1)..(self.1 + 1 + len as usize))
-            .ok_or(format_err!("unexpected end of input"))
+            .ok_or_else(|| self.end())
     }
 
-    pub fn var_next(&mut self) -> Result<&'a [u8], Error> {
+    pub fn var_next(&mut self) -> Result<&'a [u8], RejectReason> {
         let ret = self.var_peek()?;
         self.1 += 1 + ret.len();
         Ok(ret)
     }
 }
 
-pub fn decode<'a>(
-    data: &[u8],
-    out_addrs: &[&str],
-    txid: String,
-    from: String,
-    time: &'a DateTime<Utc>,
-    network: Network,
-) -> Result<BorkTxData<'a>, Error> {
-    let mut data = Cur(data, 0);
-    let mut out_addrs = Cur(out_addrs, 0);
-    if data.next()? != MAGIC[0] || data.next()? != MAGIC[1] {
-        bail!("invalid version");
-    }
-    Ok(match data.next()? {
-        0x00 => BorkTxData {
-            bork_type: BorkType::SetName,
-            content: Some(std::str::from_utf8(data.rest())?.to_owned()),
-            index: None,
-            mentions: Vec::new(),
-            nonce: None,
-            recipient_address: None,
-            reference_id: None,
-            sender_address: from,
-            time,
-            txid,
+/// Decodes one part of a message. `network` decides how the addresses in
+/// follows and blocks are written. Messages of another protocol version, or
+/// of a type this version does not define, decode as `Unknown`.
+pub fn decode(data: &[u8], network: Network) -> Result<BorkMessage, Error> {
+    if data.first() != Some(&PROTOCOL_MARKER) {
+        bail!(Decode, "not a bork message");
+    }
+    decode_payload(data, network).map_err(|reason| err!(Decode, "{}", reason))
+}
+
+/// Decodes a part known to start with `PROTOCOL_MARKER`.
+fn decode_payload(data: &[u8], network: Network) -> Result<BorkMessage, RejectReason> {
+    let mut data = Cur(data, 1);
+    let version = data.next()?;
+    let message_type = data.next()?;
+    if version != PROTOCOL_VERSION {
+        return Ok(BorkMessage::Unknown {
+            version,
+            message_type,
+            payload: data.rest().to_vec(),
+        });
+    }
+    Ok(match message_type {
+        0x00 => BorkMessage::SetName {
+            content: utf8(data.rest())?,
+        },
+        0x01 => BorkMessage::SetBio {
+            content: utf8(data.rest())?,
+        },
+        0x02 => BorkMessage::SetAvatar {
+            content: utf8(data.rest())?,
         },
-        0x01 => BorkTxData {
-            bork_type: BorkType::SetBio,
-            content: Some(std::str::from_utf8(data.rest())?.to_owned()),
-            index: None,
-            mentions: Vec::new(),
-            nonce: None,
-            recipient_address: None,
-            reference_id: None,
-            sender_address: from,
-            time,
-            txid,
+        0x03 => BorkMessage::Bork {
+            nonce: data.next()?,
+            content: utf8(data.rest())?,
         },
-        0x02 => BorkTxData {
-            bork_type: BorkType::SetAvatar,
-            content: Some(std::str::from_utf8(data.rest())?.to_owned()),
-            index: None,
-            mentions: Vec::new(),
-            nonce: None,
-            recipient_address: None,
-            reference_id: None,
-            sender_address: from,
-            time,
-            txid,
+        0x04 => BorkMessage::Comment {
+            nonce: data.next()?,
+            reference_id: data.var_next()?.to_vec(),
+            content: utf8(data.rest())?,
         },
-        0x03 => BorkTxData {
-            bork_type: BorkType::Bork,
-            index: Some(0),
-            mentions: out_addrs
-                .rest()
-                .into_iter()
-                .map(|a| a.clone().to_owned())
-                .collect(),
-            nonce: Some(data.next()?),
-            content: Some(std::str::from_utf8(data.rest())?.to_owned()),
-            recipient_address: None,
-            reference_id: None,
-            sender_address: from,
-            time,
-            txid,
+        0x05 => BorkMessage::Rebork {
+            nonce: data.next()?,
+            reference_id: data.var_next()?.to_vec(),
+            content: utf8(data.rest())?,
         },
-        0x04 => BorkTxData {
-            bork_type: BorkType::Comment,
-            index: Some(0),
-            recipient_address: Some(out_addrs.next()?.to_owned()),
-            mentions: out_addrs
-                .rest()
-                .into_iter()
-                .map(|a| a.clone().to_owned())
-                .collect(),
-            nonce: Some(data.next()?),
-            reference_id: Some(hex::encode(data.var_next()?)),
-            content: Some(std::str::from_utf8(data.rest())?.to_owned()),
-            sender_address: from,
-            time,
-            txid,
+        0x06 => BorkMessage::Extension {
+            nonce: data.next()?,
+            index: data.next()?,
+            content: utf8(data.rest())?,
         },
-        0x05 => BorkTxData {
-            bork_type: BorkType::Rebork,
-            index: Some(0),
-            recipient_address: Some(out_addrs.next()?.to_owned()),
-            mentions: out_addrs
-                .rest()
-                .into_iter()
-                .map(|a| a.clone().to_owned())
-                .collect(),
-            nonce: Some(data.next()?),
-            reference_id: Some(hex::encode(data.var_next()?)),
-            content: Some(std::str::from_utf8(data.rest())?.to_owned()),
-            sender_address: from,
-            time,
-            txid,
+        0x07 => BorkMessage::Delete {
+            reference_id: data.var_next()?.to_vec(),
         },
-        0x06 => BorkTxData {
-            bork_type: BorkType::Extension,
-            recipient_address: None,
-            mentions: out_addrs
-                .rest()
-                .into_iter()
-                .map(|a| a.clone().to_owned())
-                .collect(),
-            nonce: Some(data.next()?),
-            index: Some(data.next()?),
-            reference_id: None,
-            content: Some(std::str::from_utf8(data.rest())?.to_owned()),
-            sender_address: from,
-            time,
-            txid,
+        0x08 => BorkMessage::Like {
+            reference_id: data.var_next()?.to_vec(),
         },
-        0x07 => BorkTxData {
-            bork_type: BorkType::Delete,
-            recipient_address: None,
-            mentions: Vec::new(),
-            nonce: None,
-            index: None,
-            reference_id: Some(hex::encode(data.var_next()?)),
-            content: None,
-            sender_address: from,
-            time,
-            txid,
+        0x09 => BorkMessage::Unlike {
+            reference_txid: data.next_n(32)?.to_vec(),
         },
-        0x08 => BorkTxData {
-            bork_type: BorkType::Like,
-            recipient_address: Some(out_addrs.next()?.to_owned()),
-            mentions: Vec::new(),
-            nonce: None,
-            index: None,
-            reference_id: Some(hex::encode(data.var_next()?)),
-            content: None,
-            sender_address: from,
-            time,
-            txid,
+        0x0A => BorkMessage::Flag {
+            reference_txid: data.next_n(32)?.to_vec(),
         },
-        0x09 => BorkTxData {
-            bork_type: BorkType::Unlike,
-            recipient_address: None,
-            mentions: Vec::new(),
-            nonce: None,
-            index: None,
-            reference_id: Some(hex::encode(data.next_n(32)?)),
-            content: None,
-            sender_address: from,
-            time,
-            txid,
+        0x0B => BorkMessage::Unflag {
+            reference_txid: data.next_n(32)?.to_vec(),
         },
-        0x0A => BorkTxData {
-            bork_type: BorkType::Flag,
-            recipient_address: None,
-            mentions: Vec::new(),
-            nonce: None,
-            index: None,
-            reference_id: Some(hex::encode(data.next_n(32)?)),
-            content: None,
-            sender_address: from,
-            time,
-            txid,
+        0x0C => BorkMessage::Follow {
+            address: pubkey_hash_to_addr(data.next_n(20)?, network),
         },
-        0x0B => BorkTxData {
-            bork_type: BorkType::Unflag,
-            recipient_address: None,
-            mentions: Vec::new(),
-            nonce: None,
-            index: None,
-            reference_id: Some(hex::encode(data.next_n(32)?)),
-            content: None,
-            sender_address: from,
-            time,
-            txid,
+        0x0D => BorkMessage::Unfollow {
+            address: pubkey_hash_to_addr(data.next_n(20)?, network),
         },
-        0x0C => BorkTxData {
-            bork_type: BorkType::Follow,
-            recipient_address: None,
-            mentions: Vec::new(),
-            nonce: None,
-            index: None,
-            reference_id: None,
-            content: Some(pubkey_hash_to_addr(data.next_n(20)?, network)),
-            sender_address: from,
-            time,
-            txid,
+        0x0E => BorkMessage::Block {
+            address: pubkey_hash_to_addr(data.next_n(20)?, network),
         },
-        0x0D => BorkTxData {
-            bork_type: BorkType::Unfollow,
-            recipient_address: None,
-            mentions: Vec::new(),
-            nonce: None,
-            index: None,
-            reference_id: None,
-            content: Some(pubkey_hash_to_addr(data.next_n(20)?, network)),
-            sender_address: from,
-            time,
-            txid,
+        0x0F => BorkMessage::Unblock {
+            address: pubkey_hash_to_addr(data.next_n(20)?, network),
         },
-        0x0E => BorkTxData {
-            bork_type: BorkType::Block,
-            recipient_address: None,
-            mentions: Vec::new(),
-            nonce: None,
-            index: None,
-            reference_id: None,
-            content: Some(pubkey_hash_to_addr(data.next_n(20)?, network)),
-            sender_address: from,
-            time,
-            txid,
+        0x10 => BorkMessage::DirectMessage {
+            nonce: data.next()?,
+            ciphertext: data.rest().to_vec(),
         },
-        0x0F => BorkTxData {
-            bork_type: BorkType::Unblock,
-            recipient_address: None,
-            mentions: Vec::new(),
-            nonce: None,
-            index: None,
-            reference_id: None,
-            content: Some(pubkey_hash_to_addr(data.next_n(20)?, network)),
-            sender_address: from,
-            time,
-            txid,
+        0x11 => BorkMessage::DirectMessageExtension {
+            nonce: data.next()?,
+            index: data.next()?,
+            ciphertext: data.rest().to_vec(),
+        },
+        _ => BorkMessage::Unknown {
+            version,
+            message_type,
+            payload: data.rest().to_vec(),
+        },
+    })
+}
+
+fn utf8(content: &[u8]) -> Result<String, RejectReason> {
+    match std::str::from_utf8(content) {
+        Ok(s) => Ok(s.to_owned()),
+        Err(e) => Err(RejectReason::InvalidUtf8(e.valid_up_to())),
+    }
+}
+
+/// The address whose key validly signed input 0, which is the author of any
+/// bork in `tx`. A P2WPKH spend is only checked, and its author credited,
+/// if `prevouts` knows the output it spends; its author is identified by
+/// the P2PKH address of the same key.
+pub fn tx_author<P: PrevoutSource>(
+    tx: &bitcoin::Transaction,
+    network: Network,
+    prevouts: &mut P,
+) -> Option<String> {
+    recover_author(tx, network, prevouts).map(|(addr, _)| addr)
+}
+
+/// Like `tx_author`, but also reports how input 0 was signed.
+pub fn recover_author<P: PrevoutSource>(
+    tx: &bitcoin::Transaction,
+    network: Network,
+    prevouts: &mut P,
+) -> Option<(String, AuthorKind)> {
+    author_of(tx, network, prevouts).ok()
+}
+
+/// Like `recover_author`, but says why there is no author.
+fn author_of<P: PrevoutSource>(
+    tx: &bitcoin::Transaction,
+    network: Network,
+    prevouts: &mut P,
+) -> Result<(String, AuthorKind), RejectReason> {
+    let i = tx.input.first().ok_or(RejectReason::UnsupportedInput)?;
+    if i.script_sig.is_empty() && !i.witness.is_empty() {
+        return witness_author(tx, network, prevouts)
+            .map(|addr| (addr, AuthorKind::WitnessPubkeyHash));
+    }
+    let pushes = script_pushes(&i.script_sig).ok_or(RejectReason::UnsupportedInput)?;
+    if pushes.len() == 2 {
+        pubkey_hash_author(tx, pushes[0], pushes[1], network)
+            .map(|addr| (addr, AuthorKind::PubkeyHash))
+    } else if pushes.len() > 2 && pushes[0].is_empty() {
+        let redeem = pushes[pushes.len() - 1];
+        multisig_author(tx, &pushes[1..pushes.len() - 1], redeem, network)
+            .map(|addr| (addr, AuthorKind::Multisig))
+    } else {
+        Err(RejectReason::UnsupportedInput)
+    }
+}
+
+/// The data pushed by `script`, or `None` if it does anything but push.
+fn script_pushes(script: &bitcoin::Script) -> Option<Vec<&[u8]>> {
+    script
+        .iter(true)
+        .map(|ins| match ins {
+            bitcoin::blockdata::script::Instruction::PushBytes(b) => Some(b),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The P2PKH addresses of the keys that spend the P2PKH and P2WPKH inputs
+/// of `tx`. Outputs paying them are change, such as a sponsor's or a
+/// funder's, rather than recipients.
+fn input_signers(tx: &bitcoin::Transaction, network: Network) -> Vec<String> {
+    tx.input
+        .iter()
+        .filter_map(|i| {
+            let pubkey = if i.witness.is_empty() {
+                match script_pushes(&i.script_sig)?.as_slice() {
+                    [_, pubkey] => pubkey.to_vec(),
+                    _ => return None,
+                }
+            } else {
+                match i.witness.as_slice() {
+                    [_, pubkey] => pubkey.clone(),
+                    _ => return None,
+                }
+            };
+            Some(pubkey_to_addr(&pubkey, network))
+        })
+        .collect()
+}
+
+/// The public key that signed input 0 of `tx`, if it was spent from a
+/// single key. Any bork reveals its author's key, which is what direct
+/// messages to or from them are encrypted with.
+pub fn author_pubkey<P: PrevoutSource>(
+    tx: &bitcoin::Transaction,
+    network: Network,
+    prevouts: &mut P,
+) -> Option<Vec<u8>> {
+    let i = tx.input.first()?;
+    match recover_author(tx, network, prevouts)?.1 {
+        AuthorKind::PubkeyHash => match i.script_sig.iter(true).last()? {
+            bitcoin::blockdata::script::Instruction::PushBytes(b) => Some(b.to_vec()),
+            _ => None,
+        },
+        AuthorKind::Multisig => None,
+        AuthorKind::WitnessPubkeyHash => i.witness.get(1).cloned(),
+    }
+}
+
+/// Splits a signature push into its DER signature and sighash type.
+fn split_sig(sig: &[u8]) -> Option<(secp256k1::Signature, u32)> {
+    let (sighash_type, der) = sig.split_last()?;
+    Some((
+        secp256k1::Signature::parse_der_lax(der).ok()?,
+        *sighash_type as u32,
+    ))
+}
+
+fn check_sig(
+    tx: &bitcoin::Transaction,
+    script_code: &bitcoin::Script,
+    sig: &[u8],
+    pubkey: &secp256k1::PublicKey,
+) -> bool {
+    use bitcoin_hashes::Hash;
+
+    let (sig, sighash_type) = match split_sig(sig) {
+        Some(s) => s,
+        None => return false,
+    };
+    let msg =
+        secp256k1::Message::parse(&tx.signature_hash(0, script_code, sighash_type).into_inner());
+    secp256k1::verify(&msg, &sig, pubkey)
+}
+
+/// Author of a P2PKH spend, with a compressed or uncompressed key.
+fn pubkey_hash_author(
+    tx: &bitcoin::Transaction,
+    sig: &[u8],
+    pubkey: &[u8],
+    network: Network,
+) -> Result<String, RejectReason> {
+    let addr = pubkey_to_addr(pubkey, network);
+    let key = secp256k1::PublicKey::parse_slice(pubkey, None)
+        .map_err(|_| RejectReason::UnsupportedInput)?;
+    let script_code = addr_to_script(&addr).map_err(|_| RejectReason::UnsupportedInput)?;
+    if !check_sig(tx, &script_code, sig, &key) {
+        return Err(RejectReason::BadSignature);
+    }
+    Ok(addr)
+}
+
+/// Author of a P2WPKH spend of the output `prevouts` knows for input 0,
+/// identified by the P2PKH address of its key. Only `SIGHASH_ALL`
+/// signatures are checked, the only kind BIP143 support here covers.
+fn witness_author<P: PrevoutSource>(
+    tx: &bitcoin::Transaction,
+    network: Network,
+    prevouts: &mut P,
+) -> Result<String, RejectReason> {
+    use bitcoin::util::bip143::SighashComponents;
+    use bitcoin_hashes::Hash;
+
+    let i = tx.input.first().ok_or(RejectReason::UnsupportedInput)?;
+    let (sig, pubkey) = match i.witness.as_slice() {
+        [sig, pubkey] => (sig, pubkey),
+        _ => return Err(RejectReason::UnsupportedInput),
+    };
+    let key = secp256k1::PublicKey::parse_slice(pubkey, Some(PublicKeyFormat::Compressed))
+        .map_err(|_| RejectReason::UnsupportedInput)?;
+    let pkh = hash160(pubkey);
+    let prevout = prevouts
+        .prevout(&i.previous_output)
+        .ok_or(RejectReason::MissingPrevout)?;
+    if prevout.script_pubkey != p2wpkh_script(&pkh) {
+        return Err(RejectReason::UnsupportedInput);
+    }
+    let (sig, sighash_type) = split_sig(sig).ok_or(RejectReason::BadSignature)?;
+    if sighash_type != 0x01 {
+        return Err(RejectReason::UnsupportedInput);
+    }
+    let addr = pubkey_hash_to_addr(&pkh, network);
+    let script_code = addr_to_script(&addr).map_err(|_| RejectReason::UnsupportedInput)?;
+    let sighash = SighashComponents::new(tx).sighash_all(i, &script_code, prevout.value);
+    let msg = secp256k1::Message::parse(&sighash.into_inner());
+    if !secp256k1::verify(&msg, &sig, &key) {
+        return Err(RejectReason::BadSignature);
+    }
+    Ok(addr)
+}
+
+/// Reads an `m`-of-`n` CHECKMULTISIG redeem script, returning `m` and the
+/// keys.
+fn parse_multisig(redeem: &[u8]) -> Option<(usize, Vec<&[u8]>)> {
+    let (&checkmultisig, rest) = redeem.split_last()?;
+    let (&n, rest) = rest.split_last()?;
+    let (&m, mut rest) = rest.split_first()?;
+    let pushnum = 0x51..=0x60;
+    if checkmultisig != 0xAE || !pushnum.contains(&m) || !pushnum.contains(&n) {
+        return None;
+    }
+    let (m, n) = ((m - 0x50) as usize, (n - 0x50) as usize);
+
+    let mut keys = Vec::new();
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = len as usize;
+        if (len != 33 && len != 65) || tail.len() < len {
+            return None;
+        }
+        keys.push(&tail[..len]);
+        rest = &tail[len..];
+    }
+    if keys.len() != n || m > n {
+        return None;
+    }
+    Some((m, keys))
+}
+
+/// Author of a P2SH multisig spend, identified by the P2SH address. The
+/// signatures must match the keys in order, as with CHECKMULTISIG.
+fn multisig_author(
+    tx: &bitcoin::Transaction,
+    sigs: &[&[u8]],
+    redeem: &[u8],
+    network: Network,
+) -> Result<String, RejectReason> {
+    let (m, keys) = parse_multisig(redeem).ok_or(RejectReason::UnsupportedInput)?;
+    if sigs.len() != m {
+        return Err(RejectReason::BadSignature);
+    }
+    let script_code = bitcoin::Script::from(redeem.to_vec());
+    let mut keys = keys.into_iter();
+    for sig in sigs {
+        let matched = keys.any(|key| match secp256k1::PublicKey::parse_slice(key, None) {
+            Ok(key) => check_sig(tx, &script_code, sig, &key),
+            Err(_) => false,
+        });
+        if !matched {
+            return Err(RejectReason::BadSignature);
+        }
+    }
+    Ok(redeem_script_to_addr(redeem, network))
+}
+
+/// The index of the last OP_RETURN output of `tx`, and the data it pushes.
+/// `decode_tx` rejects transactions with more than one.
+fn op_return_data(tx: &bitcoin::Transaction) -> Option<(u32, &[u8])> {
+    let (idx, o) = tx
+        .output
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, o)| o.script_pubkey.is_op_return())?;
+    let b = o.script_pubkey.as_bytes();
+    let data = match b.get(1) {
+        Some(0x4c) => b.get(3..),
+        Some(0x4d) => b.get(4..),
+        Some(0x4e) => b.get(6..),
+        _ => b.get(2..),
+    }?;
+    Some((idx as u32, data))
+}
+
+fn decode_tx<P: PrevoutSource>(
+    tx: &bitcoin::Transaction,
+    (output_index, data): (u32, &[u8]),
+    time: DateTime<Utc>,
+    network: Network,
+    prevouts: &mut P,
+) -> Result<BorkTx, RejectReason> {
+    // A SIGHASH_SINGLE author signature leaves every output but one open,
+    // so a second OP_RETURN could carry content the author never signed.
+    let op_returns = tx
+        .output
+        .iter()
+        .filter(|o| o.script_pubkey.is_op_return())
+        .count();
+    if op_returns > 1 {
+        return Err(RejectReason::MultipleOpReturns(op_returns));
+    }
+    let (from, author_kind) = author_of(tx, network, prevouts)?;
+    let message = decode_payload(data, network)?;
+    let signers = input_signers(tx, network);
+    let mut recipients = tx
+        .output
+        .iter()
+        .filter(|o| script_to_addr(&o.script_pubkey, network).is_ok())
+        .filter_map(|o| script_pubkey_hash(&o.script_pubkey))
+        .map(|pkh| pubkey_hash_to_addr(pkh, network))
+        .filter(|a| a != &from && !signers.contains(a));
+    let recipient_address = match message {
+        BorkMessage::Comment { .. }
+        | BorkMessage::Rebork { .. }
+        | BorkMessage::Like { .. }
+        | BorkMessage::DirectMessage { .. } => match recipients.next() {
+            Some(addr) => Some(addr),
+            None => return Err(RejectReason::MissingRecipient),
         },
-        _ => bail!("invalid message type"),
+        _ => None,
+    };
+    let mentions = match message {
+        BorkMessage::Bork { .. }
+        | BorkMessage::Comment { .. }
+        | BorkMessage::Rebork { .. }
+        | BorkMessage::Extension { .. } => recipients.collect(),
+        _ => Vec::new(),
+    };
+    Ok(BorkTx {
+        time,
+        txid: format!("{:x}", tx.txid()),
+        output_index,
+        block_hash: None,
+        block_height: None,
+        message,
+        sender_address: from,
+        author_kind,
+        recipient_address,
+        mentions,
     })
 }
 
-pub fn parse_tx<'a>(
+/// Decodes the bork in `tx` like `parse_tx`, but reports why it was
+/// rejected instead of dropping it. Returns `None` if `tx` has no OP_RETURN
+/// starting with `PROTOCOL_MARKER`.
+pub fn diagnose_tx<P: PrevoutSource>(
+    tx: &bitcoin::Transaction,
+    time: DateTime<Utc>,
+    network: Network,
+    prevouts: &mut P,
+) -> Option<Result<BorkTx, Rejection>> {
+    let data = op_return_data(tx).filter(|(_, data)| data.first() == Some(&PROTOCOL_MARKER))?;
+    Some(
+        decode_tx(tx, data, time, network, prevouts).map_err(|reason| Rejection {
+            txid: format!("{:x}", tx.txid()),
+            reason,
+        }),
+    )
+}
+
+/// Decodes the bork in `tx`, if any, and lists the outputs it spends and
+/// creates. `prevouts` is used to check segwit authors.
+pub fn parse_tx<P: PrevoutSource>(
     tx: bitcoin::Transaction,
-    time: &'a DateTime<Utc>,
+    time: DateTime<Utc>,
     network: Network,
-) -> (Option<BorkTxData<'a>>, Vec<UtxoId>, Vec<NewUtxo>) {
+    prevouts: &mut P,
+) -> (Option<BorkTx>, Vec<UtxoId>, Vec<NewUtxo>) {
     use bitcoin::consensus::Encodable;
 
     let mut tx_data: Vec<u8> = Vec::new();
     tx.consensus_encode(&mut tx_data).unwrap();
-    let from = {
-        tx.input.get(0).and_then(|i| {
-            use bitcoin_hashes::Hash;
-
-            let mut script = i.script_sig.iter(true);
-            let mut sig = match script.next() {
-                Some(bitcoin::blockdata::script::Instruction::PushBytes(b)) => b.to_vec(),
-                _ => return None,
-            };
-            let pubkey = match script.next() {
-                Some(bitcoin::blockdata::script::Instruction::PushBytes(b)) => b,
-                _ => return None,
-            };
-            if sig.len() == 0 {
-                return None;
-            }
-            let sighash_type = sig.remove(sig.len() - 1);
-            let addr = pubkey_to_addr(pubkey, network);
-            let msg = secp256k1::Message::parse_slice(
-                &tx.signature_hash(0, &addr_to_script(&addr).ok()?, sighash_type as u32)
-                    .into_inner(),
-            )
-            .ok()?;
-            if !secp256k1::verify(
-                &msg,
-                &secp256k1::Signature::parse_der_lax(&sig).ok()?,
-                &secp256k1::PublicKey::parse_slice(
-                    pubkey,
-                    Some(secp256k1::PublicKeyFormat::Compressed),
-                )
-                .ok()?,
-            ) {
-                return None;
-            }
-
-            Some(addr)
-        })
-    };
     let tx_hex = hex::encode(tx_data);
     let txid = format!("{:x}", tx.txid());
-    let mut op_ret = None;
+    let bork =
+        op_return_data(&tx).and_then(|data| decode_tx(&tx, data, time, network, prevouts).ok());
     let mut spent = Vec::new();
     let mut created = Vec::new();
     for (idx, o) in tx.output.iter().enumerate() {
-        if o.script_pubkey.is_p2pkh() {
+        if script_pubkey_hash(&o.script_pubkey).is_some() {
+            let address = match script_to_addr(&o.script_pubkey, network) {
+                Ok(address) => address,
+                Err(_) => continue,
+            };
             created.push(NewUtxo {
                 txid: txid.clone(),
                 index: idx as u32,
-                address: crate::wallet::script_to_addr(&o.script_pubkey, network).unwrap(),
+                address,
                 value: o.value,
                 raw: tx_hex.clone(),
             });
-        } else if o.script_pubkey.is_op_return() {
-            let b = o.script_pubkey.as_bytes();
-            match b.get(1) {
-                Some(0x4c) => op_ret = b.get(3..),
-                Some(0x4d) => op_ret = b.get(4..),
-                Some(0x4e) => op_ret = b.get(6..),
-                _ => op_ret = b.get(2..),
-            };
         }
     }
     for (idx, i) in tx.input.into_iter().enumerate() {
@@ -735,25 +1081,578 @@ pub fn parse_tx<'a>(
         });
     }
 
-    let bork = op_ret.and_then(|data| {
-        from.and_then(|from| {
-            decode(
-                data,
-                created
-                    .iter()
-                    .map(|c| c.address.as_str())
-                    .filter(|a| a != &from.as_str())
-                    .collect::<Vec<&str>>()
-                    .as_slice(),
-                txid,
-                from,
-                time,
-                network,
-            )
-            .ok()
-        })
-    });
-
 
     (bork, spent, created)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    const NETWORKS: [Network; 3] = [Network::Dogecoin, Network::Litecoin, Network::Bitcoin];
+
+    /// Random text of at most `max` bytes, mixing one to four byte chars.
+    fn text(rng: &mut SmallRng, max: usize) -> String {
+        let chars = ['a', 'Z', ' ', 'é', 'ß', '中', '語', '🐕', '🎉'];
+        let len = rng.gen_range(0, max + 1);
+        let mut s = String::new();
+        loop {
+            let c = chars[rng.gen_range(0, chars.len())];
+            if s.len() + c.len_utf8() > len {
+                return s;
+            }
+            s.push(c);
+        }
+    }
+
+    fn bytes(rng: &mut SmallRng, max: usize) -> Vec<u8> {
+        let len = rng.gen_range(0, max + 1);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn txid(rng: &mut SmallRng) -> Vec<u8> {
+        (0..32).map(|_| rng.gen()).collect()
+    }
+
+    fn address(rng: &mut SmallRng, network: Network) -> String {
+        let pkh: Vec<u8> = (0..20).map(|_| rng.gen()).collect();
+        pubkey_hash_to_addr(&pkh, network)
+    }
+
+    /// One random message of every variant, each small enough for one part.
+    fn messages(rng: &mut SmallRng, network: Network) -> Vec<BorkMessage> {
+        let comment_ref = bytes(rng, 32);
+        let rebork_ref = bytes(rng, 32);
+        let mut other_version = rng.gen();
+        if other_version == PROTOCOL_VERSION {
+            other_version += 1;
+        }
+        vec![
+            BorkMessage::SetName {
+                content: text(rng, 77),
+            },
+            BorkMessage::SetBio {
+                content: text(rng, 77),
+            },
+            BorkMessage::SetAvatar {
+                content: text(rng, 77),
+            },
+            BorkMessage::Bork {
+                nonce: rng.gen(),
+                content: text(rng, 76),
+            },
+            BorkMessage::Comment {
+                nonce: rng.gen(),
+                content: text(rng, 75 - comment_ref.len()),
+                reference_id: comment_ref,
+            },
+            BorkMessage::Rebork {
+                nonce: rng.gen(),
+                content: text(rng, 75 - rebork_ref.len()),
+                reference_id: rebork_ref,
+            },
+            BorkMessage::Extension {
+                nonce: rng.gen(),
+                index: rng.gen(),
+                content: text(rng, 75),
+            },
+            BorkMessage::Delete {
+                reference_id: bytes(rng, 32),
+            },
+            BorkMessage::Like {
+                reference_id: bytes(rng, 32),
+            },
+            BorkMessage::Unlike {
+                reference_txid: txid(rng),
+            },
+            BorkMessage::Flag {
+                reference_txid: txid(rng),
+            },
+            BorkMessage::Unflag {
+                reference_txid: txid(rng),
+            },
+            BorkMessage::Follow {
+                address: address(rng, network),
+            },
+            BorkMessage::Unfollow {
+                address: address(rng, network),
+            },
+            BorkMessage::Block {
+                address: address(rng, network),
+            },
+            BorkMessage::Unblock {
+                address: address(rng, network),
+            },
+            BorkMessage::DirectMessage {
+                nonce: rng.gen(),
+                ciphertext: bytes(rng, 76),
+            },
+            BorkMessage::DirectMessageExtension {
+                nonce: rng.gen(),
+                index: rng.gen(),
+                ciphertext: bytes(rng, 75),
+            },
+            BorkMessage::Unknown {
+                version: other_version,
+                message_type: rng.gen(),
+                payload: bytes(rng, 77),
+            },
+            BorkMessage::Unknown {
+                version: PROTOCOL_VERSION,
+                message_type: rng.gen_range(u32::from(LAST_KNOWN_TYPE) + 1, 0x100) as u8,
+                payload: bytes(rng, 77),
+            },
+        ]
+    }
+
+    /// Joins the extensions in `parts` back onto the first message.
+    fn reassemble(parts: &[Vec<u8>], network: Network) -> BorkMessage {
+        let mut message = decode(&parts[0], network).unwrap();
+        for (i, part) in parts.iter().enumerate().skip(1) {
+            match (&mut message, decode(part, network).unwrap()) {
+                (
+                    BorkMessage::Bork { nonce, content }
+                    | BorkMessage::Comment { nonce, content, .. }
+                    | BorkMessage::Rebork { nonce, content, .. },
+                    BorkMessage::Extension {
+                        nonce: n,
+                        index,
+                        content: c,
+                    },
+                ) if *nonce == n && index as usize == i => content.push_str(&c),
+                (
+                    BorkMessage::DirectMessage { nonce, ciphertext },
+                    BorkMessage::DirectMessageExtension {
+                        nonce: n,
+                        index,
+                        ciphertext: c,
+                    },
+                ) if *nonce == n && index as usize == i => ciphertext.extend(c),
+                (_, part) => panic!("unexpected part {} {:?}", i, part),
+            }
+        }
+        message
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        for seed in 0..64 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            for network in NETWORKS.iter() {
+                for message in messages(&mut rng, *network) {
+                    let parts = encode(&message).unwrap();
+                    assert_eq!(parts.len(), 1, "{:?}", message);
+                    assert!(parts[0].len() <= MAX_PART_LEN, "{:?}", message);
+                    assert_eq!(decode(&parts[0], *network).unwrap(), message);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_messages_round_trip_through_extensions() {
+        for seed in 0..64 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let reference_id = bytes(&mut rng, 32);
+            let messages = vec![
+                BorkMessage::Bork {
+                    nonce: rng.gen(),
+                    content: text(&mut rng, 2000),
+                },
+                BorkMessage::Comment {
+                    nonce: rng.gen(),
+                    reference_id: reference_id.clone(),
+                    content: text(&mut rng, 2000),
+                },
+                BorkMessage::Rebork {
+                    nonce: rng.gen(),
+                    reference_id,
+                    content: text(&mut rng, 2000),
+                },
+                BorkMessage::DirectMessage {
+                    nonce: rng.gen(),
+                    ciphertext: bytes(&mut rng, 2000),
+                },
+            ];
+            for message in messages {
+                let parts = encode(&message).unwrap();
+                assert!(parts.iter().all(|p| p.len() <= MAX_PART_LEN));
+                assert_eq!(reassemble(&parts, Network::Bitcoin), message);
+            }
+        }
+    }
+
+    #[test]
+    fn multi_byte_chars_on_the_part_boundary_stay_whole() {
+        // A bork's first part has 4 header bytes, leaving 76 for content.
+        let room = MAX_PART_LEN - 4;
+        for c in &['é', '€', '🐕'] {
+            let width = c.len_utf8();
+            // From the char ending exactly on the boundary to it starting
+            // there, through every way of straddling it.
+            for pad in room - width..=room {
+                let content = format!("{}{}", "a".repeat(pad), c.to_string().repeat(100));
+                let message = BorkMessage::Bork { nonce: 7, content };
+                let parts = encode(&message).unwrap();
+                let head = if pad + width == room {
+                    MAX_PART_LEN
+                } else {
+                    4 + pad
+                };
+                assert_eq!(parts[0].len(), head, "{:?} after {}", c, pad);
+                assert!(parts.iter().all(|p| p.len() <= MAX_PART_LEN));
+                // Every extension but the last is only short of the limit
+                // by less than the char that would not fit.
+                for p in &parts[1..parts.len() - 1] {
+                    assert!(MAX_PART_LEN - p.len() < width, "{:?} after {}", c, pad);
+                }
+                assert_eq!(reassemble(&parts, Network::Bitcoin), message);
+            }
+        }
+    }
+
+    fn refused(message: &BorkMessage) -> bool {
+        matches!(encode(message), Err(Error::Encode(_)))
+    }
+
+    #[test]
+    fn content_up_to_the_maximum_length_is_accepted() {
+        let bork = |len| BorkMessage::Bork {
+            nonce: 0,
+            content: "a".repeat(len),
+        };
+        let parts = encode(&bork(MAX_CONTENT_LEN)).unwrap();
+        assert!(parts.len() <= MAX_PARTS);
+        assert_eq!(reassemble(&parts, Network::Bitcoin), bork(MAX_CONTENT_LEN));
+        assert!(refused(&bork(MAX_CONTENT_LEN + 1)));
+    }
+
+    #[test]
+    fn direct_messages_longer_than_max_parts_are_refused() {
+        // 76 bytes fit after the first part's header and 75 after each
+        // extension's.
+        let longest = (MAX_PART_LEN - 4) + (MAX_PARTS - 1) * (MAX_PART_LEN - 5);
+        let message = |len| BorkMessage::DirectMessage {
+            nonce: 0,
+            ciphertext: vec![0xab; len],
+        };
+        let parts = encode(&message(longest)).unwrap();
+        assert_eq!(parts.len(), MAX_PARTS);
+        assert_eq!(reassemble(&parts, Network::Bitcoin), message(longest));
+        assert!(refused(&message(longest + 1)));
+    }
+
+    #[test]
+    fn single_part_messages_over_the_part_length_are_refused() {
+        let name = |len| BorkMessage::SetName {
+            content: "a".repeat(len),
+        };
+        let parts = encode(&name(MAX_PART_LEN - 3)).unwrap();
+        assert_eq!(parts[0].len(), MAX_PART_LEN);
+        assert!(refused(&name(MAX_PART_LEN - 2)));
+        assert!(refused(&BorkMessage::Extension {
+            nonce: 0,
+            index: 1,
+            content: "a".repeat(MAX_PART_LEN - 4),
+        }));
+        assert!(refused(&BorkMessage::DirectMessageExtension {
+            nonce: 0,
+            index: 1,
+            ciphertext: vec![0; MAX_PART_LEN - 4],
+        }));
+        assert!(refused(&BorkMessage::Unknown {
+            version: PROTOCOL_VERSION,
+            message_type: LAST_KNOWN_TYPE + 1,
+            payload: vec![0; MAX_PART_LEN - 2],
+        }));
+    }
+
+    #[test]
+    fn estimate_cost_counts_every_part() {
+        let short = BorkMessage::Bork {
+            nonce: 0,
+            content: "hello".to_owned(),
+        };
+        let cost = estimate_cost(&short).unwrap();
+        assert_eq!((cost.transactions, cost.bytes), (1, 4 + 5));
+
+        let long = BorkMessage::Bork {
+            nonce: 0,
+            content: "a".repeat(1000),
+        };
+        let parts = encode(&long).unwrap();
+        let cost = estimate_cost(&long).unwrap();
+        assert_eq!(cost.transactions, parts.len());
+        // The first part has a 4 byte header and every extension 5.
+        assert_eq!(cost.bytes, 1000 + 4 + 5 * (parts.len() - 1));
+
+        let too_long = BorkMessage::Bork {
+            nonce: 0,
+            content: "a".repeat(MAX_CONTENT_LEN + 1),
+        };
+        assert!(matches!(estimate_cost(&too_long), Err(Error::Encode(_))));
+    }
+
+    #[test]
+    fn bork_tx_json_round_trips_for_every_variant() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for message in messages(&mut rng, Network::Bitcoin) {
+            let bork = BorkTx {
+                time: Utc::now(),
+                txid: hex::encode(txid(&mut rng)),
+                output_index: 2,
+                block_hash: Some(hex::encode(txid(&mut rng))),
+                block_height: Some(1_034_383),
+                message,
+                sender_address: address(&mut rng, Network::Bitcoin),
+                author_kind: AuthorKind::PubkeyHash,
+                recipient_address: Some(address(&mut rng, Network::Bitcoin)),
+                mentions: vec![address(&mut rng, Network::Bitcoin)],
+            };
+            let json = serde_json::to_string(&bork).unwrap();
+            let back: BorkTx =
+                serde_json::from_str(&json).unwrap_or_else(|e| panic!("{} in {}", e, json));
+            assert_eq!(back, bork);
+        }
+    }
+
+    #[test]
+    fn witness_spend_has_no_author() {
+        let key = secp256k1::SecretKey::parse(&[1; 32]).unwrap();
+        let victim = secp256k1::PublicKey::from_secret_key(&key);
+        let tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                previous_output: Default::default(),
+                script_sig: bitcoin::Script::new(),
+                sequence: 0xFFFFFFFF,
+                witness: vec![vec![0x01], victim.serialize_compressed().to_vec()],
+            }],
+            output: vec![],
+        };
+        assert!(recover_author(&tx, Network::Bitcoin, &mut NoPrevouts).is_none());
+    }
+
+    fn key(byte: u8) -> secp256k1::SecretKey {
+        secp256k1::SecretKey::parse(&[byte; 32]).unwrap()
+    }
+
+    fn pubkey(byte: u8) -> secp256k1::PublicKey {
+        secp256k1::PublicKey::from_secret_key(&key(byte))
+    }
+
+    fn unsigned_spend() -> bitcoin::Transaction {
+        bitcoin::Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn {
+                previous_output: Default::default(),
+                script_sig: bitcoin::Script::new(),
+                sequence: 0xFFFFFFFF,
+                witness: vec![],
+            }],
+            output: vec![bitcoin::TxOut {
+                value: 0,
+                script_pubkey: bitcoin::Script::from(vec![0x6a, 0x01, PROTOCOL_MARKER]),
+            }],
+        }
+    }
+
+    /// A `SIGHASH_ALL` signature of input 0 by `key`, as pushed.
+    fn sign(tx: &bitcoin::Transaction, script_code: &[u8], key: &secp256k1::SecretKey) -> Vec<u8> {
+        use bitcoin_hashes::Hash;
+
+        let script_code = bitcoin::Script::from(script_code.to_vec());
+        let sighash = tx.signature_hash(0, &script_code, 1).into_inner();
+        let sig = secp256k1::sign(&secp256k1::Message::parse(&sighash), key)
+            .unwrap()
+            .0;
+        [sig.serialize_der().as_ref(), &[1]].concat()
+    }
+
+    fn with_pushes(mut tx: bitcoin::Transaction, pushes: &[&[u8]]) -> bitcoin::Transaction {
+        use bitcoin::blockdata::script::Builder;
+
+        tx.input[0].script_sig = pushes
+            .iter()
+            .fold(Builder::new(), |b, p| b.push_slice(p))
+            .into_script();
+        tx
+    }
+
+    fn two_of_three() -> Vec<u8> {
+        let mut redeem = vec![0x52];
+        for byte in 1..=3 {
+            redeem.push(33);
+            redeem.extend(pubkey(byte).serialize_compressed().iter());
+        }
+        redeem.extend(&[0x53, 0xAE]);
+        redeem
+    }
+
+    #[test]
+    fn two_of_three_p2sh_spend_is_credited_to_the_script() {
+        let redeem = two_of_three();
+        let tx = unsigned_spend();
+        let (sig1, sig3) = (sign(&tx, &redeem, &key(1)), sign(&tx, &redeem, &key(3)));
+        let signed = with_pushes(tx.clone(), &[&[], &sig1, &sig3, &redeem]);
+        assert_eq!(
+            recover_author(&signed, Network::Bitcoin, &mut NoPrevouts),
+            Some((
+                redeem_script_to_addr(&redeem, Network::Bitcoin),
+                AuthorKind::Multisig
+            ))
+        );
+
+        let out_of_order = with_pushes(tx, &[&[], &sig3, &sig1, &redeem]);
+        assert_eq!(
+            recover_author(&out_of_order, Network::Bitcoin, &mut NoPrevouts),
+            None
+        );
+    }
+
+    #[test]
+    fn multisig_spend_needs_m_signatures() {
+        let redeem = two_of_three();
+        let tx = unsigned_spend();
+        let sig1 = sign(&tx, &redeem, &key(1));
+        let signed = with_pushes(tx.clone(), &[&[], &sig1, &redeem]);
+        assert!(recover_author(&signed, Network::Bitcoin, &mut NoPrevouts).is_none());
+
+        let repeated = with_pushes(tx, &[&[], &sig1, &sig1, &redeem]);
+        assert!(recover_author(&repeated, Network::Bitcoin, &mut NoPrevouts).is_none());
+    }
+
+    #[test]
+    fn uncompressed_p2pkh_author_is_credited() {
+        let compressed = pubkey_to_addr(&pubkey(1).serialize_compressed(), Network::Bitcoin);
+        let pubkey = pubkey(1).serialize();
+        let addr = pubkey_to_addr(&pubkey, Network::Bitcoin);
+        assert_ne!(addr, compressed);
+        let tx = unsigned_spend();
+        let sig = sign(&tx, addr_to_script(&addr).unwrap().as_bytes(), &key(1));
+        let signed = with_pushes(tx, &[&sig, &pubkey]);
+        assert_eq!(
+            recover_author(&signed, Network::Bitcoin, &mut NoPrevouts),
+            Some((addr, AuthorKind::PubkeyHash))
+        );
+    }
+
+    #[test]
+    fn forged_signature_has_no_author() {
+        let victim = pubkey(1).serialize_compressed();
+        let addr = pubkey_to_addr(&victim, Network::Bitcoin);
+        let script_code = addr_to_script(&addr).unwrap();
+        let tx = unsigned_spend();
+
+        let forged = sign(&tx, script_code.as_bytes(), &key(2));
+        let signed = with_pushes(tx.clone(), &[&forged, &victim]);
+        assert!(recover_author(&signed, Network::Bitcoin, &mut NoPrevouts).is_none());
+
+        let sig = sign(&tx, script_code.as_bytes(), &key(1));
+        let mut tampered = with_pushes(tx, &[&sig, &victim]);
+        assert!(recover_author(&tampered, Network::Bitcoin, &mut NoPrevouts).is_some());
+        tampered.output[0].value = 1;
+        assert!(recover_author(&tampered, Network::Bitcoin, &mut NoPrevouts).is_none());
+    }
+
+    fn op_return(data: &[u8]) -> bitcoin::TxOut {
+        use bitcoin::blockdata::opcodes::all::OP_RETURN;
+        use bitcoin::blockdata::script::Builder;
+
+        bitcoin::TxOut {
+            value: 0,
+            script_pubkey: Builder::new()
+                .push_opcode(OP_RETURN)
+                .push_slice(data)
+                .into_script(),
+        }
+    }
+
+    /// `outputs` spent by a valid P2PKH signature of key 1.
+    fn signed_spend(outputs: Vec<bitcoin::TxOut>) -> bitcoin::Transaction {
+        let pubkey = pubkey(1).serialize_compressed();
+        let script_code = addr_to_script(&pubkey_to_addr(&pubkey, Network::Bitcoin)).unwrap();
+        let mut tx = unsigned_spend();
+        tx.output = outputs;
+        let sig = sign(&tx, script_code.as_bytes(), &key(1));
+        with_pushes(tx, &[&sig, &pubkey])
+    }
+
+    fn rejection(tx: &bitcoin::Transaction) -> RejectReason {
+        diagnose_tx(tx, Utc::now(), Network::Bitcoin, &mut NoPrevouts)
+            .unwrap()
+            .unwrap_err()
+            .reason
+    }
+
+    fn bork_data() -> Vec<u8> {
+        let message = BorkMessage::Bork {
+            nonce: 0,
+            content: "hi".to_owned(),
+        };
+        encode(&message).unwrap().remove(0)
+    }
+
+    #[test]
+    fn rejects_unsupported_inputs() {
+        let mut tx = signed_spend(vec![op_return(&bork_data())]);
+        tx.input[0].script_sig = bitcoin::Script::from(vec![0x51]);
+        assert_eq!(rejection(&tx), RejectReason::UnsupportedInput);
+    }
+
+    #[test]
+    fn rejects_segwit_spends_of_unknown_outputs() {
+        let mut tx = signed_spend(vec![op_return(&bork_data())]);
+        let pushes = script_pushes(&tx.input[0].script_sig)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.to_vec())
+            .collect();
+        tx.input[0].witness = pushes;
+        tx.input[0].script_sig = bitcoin::Script::new();
+        assert_eq!(rejection(&tx), RejectReason::MissingPrevout);
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let mut tx = signed_spend(vec![op_return(&bork_data())]);
+        tx.version = 2;
+        assert_eq!(rejection(&tx), RejectReason::BadSignature);
+    }
+
+    #[test]
+    fn rejects_truncated_payloads() {
+        let data = [PROTOCOL_MARKER, PROTOCOL_VERSION, 0x03];
+        let tx = signed_spend(vec![op_return(&data)]);
+        assert_eq!(rejection(&tx), RejectReason::Truncated(3));
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        let data = [PROTOCOL_MARKER, PROTOCOL_VERSION, 0x00, b'o', b'k', 0xFF];
+        let tx = signed_spend(vec![op_return(&data)]);
+        assert_eq!(rejection(&tx), RejectReason::InvalidUtf8(2));
+    }
+
+    #[test]
+    fn rejects_comments_without_a_recipient() {
+        let message = BorkMessage::Comment {
+            nonce: 0,
+            reference_id: vec![0xab; 2],
+            content: "yes".to_owned(),
+        };
+        let data = encode(&message).unwrap().remove(0);
+        let tx = signed_spend(vec![op_return(&data)]);
+        assert_eq!(rejection(&tx), RejectReason::MissingRecipient);
+    }
+
+    #[test]
+    fn rejects_multiple_op_returns() {
+        let tx = signed_spend(vec![op_return(&bork_data()), op_return(&bork_data())]);
+        assert_eq!(rejection(&tx), RejectReason::MultipleOpReturns(2));
+    }
+}
@@ -1,47 +1,137 @@
 
 use super::addr_to_script;
 
-use super::pubkey_hash_to_addr;
+use super::{pubkey_hash_to_addr, pubkey_hash_to_segwit_addr};
+use super::{xprv_version, xpub_version};
+use super::nonce::{NonceAllocator, OpenNonce};
+use super::path::{ChildNumber, DerivationPath, HARDENED_BIT};
+use super::direct;
+use super::key;
+use super::message;
+use super::tx::{self, Signer};
 use super::HmacSha512;
 use crate::big_array::BigArray;
+use crate::Error;
 use crate::Network;
-use failure::Error;
+use chrono::{DateTime, Utc};
 use hmac::Mac;
 use ripemd160::Digest;
 use ripemd160::Ripemd160;
 use secp256k1::curve::Scalar;
-use secp256k1::{PublicKey, SecretKey};
+use secp256k1::{PublicKey, PublicKeyFormat, SecretKey};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use std::collections::BTreeMap;
 
 #[derive(Clone)]
 pub struct ChildWallet {
     seed: [u8; 64],
     mpriv: Option<SecretKey>,
     mpub: Option<PublicKey>,
-    children: Vec<Option<ChildWallet>>,
-    hardened_children: Vec<Option<ChildWallet>>,
-    nonce: u8,
+    children: BTreeMap<u32, ChildWallet>,
+    hardened_children: BTreeMap<u32, ChildWallet>,
+    nonces: NonceAllocator,
+    depth: u8,
+    parent_fingerprint: [u8; 4],
+    child_number: u32,
+    path: DerivationPath,
+    label: Option<String>,
 }
 impl ChildWallet {
-    pub fn new(seed: [u8; 64]) -> Self {
+    pub fn new(seed: [u8; 64]) -> Result<Self, Error> {
         use rand::Rng;
         let mut rng = rand::rngs::EntropyRng::new();
         let mut res = ChildWallet {
             seed,
             mpriv: None,
             mpub: None,
-            children: Vec::new(),
-            hardened_children: Vec::new(),
-            nonce: rng.gen(),
+            children: BTreeMap::new(),
+            hardened_children: BTreeMap::new(),
+            nonces: NonceAllocator::new(rng.gen()),
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: 0,
+            path: DerivationPath::default(),
+            label: None,
         };
-        res.init();
-        res
+        res.init()?;
+        Ok(res)
     }
 
-    pub fn init(&mut self) {
-        self.init_mpriv();
-        self.init_mpub();
+    fn from_public(mpub: PublicKey, chain_code: &[u8]) -> Self {
+        use rand::Rng;
+        let mut rng = rand::rngs::EntropyRng::new();
+        let mut seed: [u8; 64] = [0; 64];
+        seed[32..64].clone_from_slice(chain_code);
+        ChildWallet {
+            seed,
+            mpriv: None,
+            mpub: Some(mpub),
+            children: BTreeMap::new(),
+            hardened_children: BTreeMap::new(),
+            nonces: NonceAllocator::new(rng.gen()),
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: 0,
+            path: DerivationPath::default(),
+            label: None,
+        }
+    }
+
+    /// Watch-only wallet from a BIP32 extended public key. It can derive
+    /// non-hardened children and produce addresses and scripts, but not sign.
+    pub fn from_xpub(xpub: &str, network: Network) -> Result<Self, Error> {
+        let data = bitcoin::util::base58::from_check(xpub)?;
+        ensure!(data.len() == 78, Decode, "invalid extended key length");
+        ensure!(
+            data[0..4] == xpub_version(network),
+            Network,
+            "extended key version does not match network"
+        );
+        let mpub = PublicKey::parse_slice(&data[45..78], Some(PublicKeyFormat::Compressed))
+            .map_err(|e| err!(Decode, "{:?}", e))?;
+        let mut res = ChildWallet::from_public(mpub, &data[13..45]);
+        res.depth = data[4];
+        res.parent_fingerprint.clone_from_slice(&data[5..9]);
+        let mut child_number: [u8; 4] = [0; 4];
+        child_number.clone_from_slice(&data[9..13]);
+        res.child_number = u32::from_be_bytes(child_number);
+        Ok(res)
+    }
+
+    fn adopt(&self, mut child: ChildWallet, child_number: u32) -> Self {
+        child.depth = self.depth + 1;
+        child.parent_fingerprint = self.fingerprint();
+        child.child_number = child_number;
+        child.path = self.path.child(ChildNumber::from_u32(child_number));
+        child
+    }
+
+    /// Recomputes the depth, parent fingerprint, child number and path of
+    /// every descendant from this wallet's own, for legacy files that did
+    /// not store them.
+    fn relink(&mut self) {
+        let fingerprint = self.fingerprint();
+        let children = self
+            .children
+            .iter_mut()
+            .map(|(i, child)| (ChildNumber::normal(*i), child));
+        let hardened_children = self
+            .hardened_children
+            .iter_mut()
+            .map(|(i, child)| (ChildNumber::hardened(*i), child));
+        for (number, child) in children.chain(hardened_children) {
+            child.depth = self.depth + 1;
+            child.parent_fingerprint = fingerprint;
+            child.child_number = number.to_u32();
+            child.path = self.path.child(number);
+            child.relink();
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), Error> {
+        self.init_mpriv()?;
+        self.init_mpub()
     }
 
     pub fn mpriv_bits(&self) -> &[u8] {
@@ -52,99 +142,171 @@ impl ChildWallet {
         &self.seed[32..64]
     }
 
-    fn init_mpriv(&mut self) {
-        self.mpriv = Some(SecretKey::parse_slice(self.mpriv_bits()).unwrap());
+    fn init_mpriv(&mut self) -> Result<(), Error> {
+        self.mpriv = Some(
+            SecretKey::parse_slice(self.mpriv_bits())
+                .map_err(|_| err!(Wallet, "invalid private key"))?,
+        );
+        Ok(())
+    }
+
+    /// The private key, which watch-only wallets do not have.
+    pub fn mpriv(&self) -> Result<&SecretKey, Error> {
+        self.mpriv
+            .as_ref()
+            .ok_or(err!(Wallet, "watch-only wallet has no private key"))
     }
 
-    pub fn mpriv(&self) -> &SecretKey {
-        self.mpriv.as_ref().expect("wallet uninitialized")
+    pub fn is_watch_only(&self) -> bool {
+        self.mpriv.is_none()
     }
 
-    fn init_mpub(&mut self) {
-        self.mpub = Some(PublicKey::from_secret_key(self.mpriv()));
+    fn init_mpub(&mut self) -> Result<(), Error> {
+        self.mpub = Some(PublicKey::from_secret_key(self.mpriv()?));
+        Ok(())
     }
 
     pub fn mpub(&self) -> &PublicKey {
         self.mpub.as_ref().expect("wallet uninitialized")
     }
 
-    pub fn nonce(&mut self) -> u8 {
-        self.nonce += 1;
-        self.nonce
+    /// Reserves a nonce now, like `allocate_nonce`.
+    pub fn nonce(&mut self) -> Result<u8, Error> {
+        self.nonces.allocate(Utc::now())
+    }
+
+    pub fn nonces(&self) -> &NonceAllocator {
+        &self.nonces
+    }
+
+    /// Reserves a nonce for a new bork. The reservation only survives if the
+    /// wallet is saved afterwards; see `Wallet::reserve_nonce`.
+    pub fn allocate_nonce(&mut self, now: DateTime<Utc>) -> Result<u8, Error> {
+        self.nonces.allocate(now)
+    }
+
+    pub fn complete_nonce(&mut self, nonce: u8, now: DateTime<Utc>) {
+        self.nonces.complete(nonce, now)
+    }
+
+    pub fn release_nonce(&mut self, nonce: u8) {
+        self.nonces.release(nonce)
     }
 
     pub fn next_child(&mut self, hardened: bool) -> Result<&mut ChildWallet, Error> {
-        if !hardened {
-            self.load_child(self.children.len() as u32, false)
+        let children = if !hardened {
+            &self.children
         } else {
-            self.load_child(self.hardened_children.len() as u32, true)
+            &self.hardened_children
+        };
+        let mut i = children.keys().next_back().map(|i| i + 1).unwrap_or(0);
+        loop {
+            match self.load_child(i, hardened) {
+                Err(Error::InvalidChild(_)) => i += 1,
+                Err(e) => return Err(e),
+                Ok(_) => break,
+            }
         }
+        self.load_child(i, hardened)
     }
 
-    pub fn load_child(&mut self, i: u32, hardened: bool) -> Result<&mut ChildWallet, Error> {
-        if !hardened {
-            let min_len = i + 1;
-            if (self.children.len() as u32) < min_len {
-                self.children.resize(min_len as usize, None);
-            }
+    fn derive_child(&self, child_number: u32) -> Result<ChildWallet, Error> {
+        let mut mac =
+            HmacSha512::new_varkey(self.chain_code()).map_err(|e| err!(Wallet, "{}", e))?;
+        let mut v = if child_number & HARDENED_BIT != 0 {
+            ensure!(
+                !self.is_watch_only(),
+                Wallet,
+                "cannot derive hardened child of a watch-only wallet"
+            );
+            [&[0x0], &self.mpriv()?.serialize()[..]].concat().to_vec()
+        } else {
+            self.mpub().serialize_compressed().to_vec()
+        };
+        v.extend(&child_number.to_be_bytes());
+        mac.input(&v);
+        let mut l: [u8; 64] = [0; 64];
+        l.clone_from_slice(mac.result().code().as_slice());
+
+        let mut ll = Scalar::default();
+        let mut ll_bytes: [u8; 32] = [0; 32];
+        ll_bytes.clone_from_slice(&l[0..32]);
+        if ll.set_b32(&ll_bytes) {
+            return Err(Error::InvalidChild(child_number));
+        }
 
-            if self.children[i as usize].is_none() {
-                let mut mac =
-                    HmacSha512::new_varkey(self.chain_code()).map_err(|e| format_err!("{}", e))?;
-                let mut v = self.mpub().serialize_compressed().to_vec();
-                v.extend(&i.to_be_bytes());
-                mac.input(&v);
-                let mut l: [u8; 64] = [0; 64];
-                l.clone_from_slice(mac.result().code().as_slice());
-                let ll: Scalar = SecretKey::parse_slice(&l[0..32])
-                    .map_err(|e| format_err!("{:?}", e))?
-                    .into();
-                let cpriv = ll + self.mpriv().clone().into();
-                let cpriv_bytes = cpriv.b32();
-                for n in 0..32 {
-                    l[n] = cpriv_bytes[n];
+        let child = match self.mpriv {
+            Some(ref mpriv) => {
+                let cpriv = ll + mpriv.clone().into();
+                if cpriv.is_zero() {
+                    return Err(Error::InvalidChild(child_number));
                 }
-                self.children[i as usize] = Some(ChildWallet::new(l));
+                l[0..32].clone_from_slice(&cpriv.b32());
+                ChildWallet::new(l)?
             }
+            None => {
+                let mut cpub = self.mpub().clone();
+                if !ll.is_zero() {
+                    cpub.tweak_add_assign(&SecretKey::parse(&ll_bytes)?)
+                        .map_err(|_| Error::InvalidChild(child_number))?;
+                }
+                ChildWallet::from_public(cpub, &l[32..64])
+            }
+        };
+        Ok(self.adopt(child, child_number))
+    }
 
-            Ok(self.children[i as usize].as_mut().unwrap())
+    /// Loads the child at index `i`, deriving it if needed. Fails with
+    /// `Error::InvalidChild` for the rare indices BIP32 considers invalid; callers
+    /// should proceed with the next index.
+    pub fn load_child(&mut self, i: u32, hardened: bool) -> Result<&mut ChildWallet, Error> {
+        ensure!(
+            i & HARDENED_BIT == 0,
+            Wallet,
+            "child index {} out of range",
+            i
+        );
+        let (children, child_number) = if !hardened {
+            (&self.children, i)
         } else {
-            let hardened_i: u32 = 2_u32.pow(31) + i;
-            let min_len = i + 1;
-            if (self.hardened_children.len() as u32) < min_len {
-                self.hardened_children.resize(min_len as usize, None);
-            }
+            (&self.hardened_children, i | HARDENED_BIT)
+        };
 
-            if self.hardened_children[i as usize].is_none() {
-                let mut mac =
-                    HmacSha512::new_varkey(self.chain_code()).map_err(|e| format_err!("{}", e))?;
-                let mut v = [&[0x0], &self.mpriv().serialize()[..]].concat().to_vec();
-                v.extend(&hardened_i.to_be_bytes());
-                mac.input(&v);
-                let mut l: [u8; 64] = [0; 64];
-                l.clone_from_slice(mac.result().code().as_slice());
-                let ll: Scalar = SecretKey::parse_slice(&l[0..32])
-                    .map_err(|e| format_err!("{:?}", e))?
-                    .into();
-                let cpriv = ll + self.mpriv().clone().into();
-                let cpriv_bytes = cpriv.b32();
-                for n in 0..32 {
-                    l[n] = cpriv_bytes[n];
-                }
-                self.hardened_children[i as usize] = Some(ChildWallet::new(l));
-            }
+        let child = if children.contains_key(&i) {
+            None
+        } else {
+            Some(self.derive_child(child_number)?)
+        };
 
-            Ok(self.hardened_children[i as usize].as_mut().unwrap())
+        let children = if !hardened {
+            &mut self.children
+        } else {
+            &mut self.hardened_children
+        };
+        if let Some(child) = child {
+            children.insert(i, child);
         }
+
+        Ok(children.get_mut(&i).unwrap())
+    }
+
+    pub fn derive(&mut self, path: &DerivationPath) -> Result<&mut ChildWallet, Error> {
+        let mut res = self;
+        for n in path.children() {
+            res = res.load_child(n.index, n.hardened)?;
+        }
+        Ok(res)
+    }
+
+    pub fn derive_path(&mut self, path: &str) -> Result<&mut ChildWallet, Error> {
+        self.derive(&path.parse()?)
     }
 
     pub fn get_child(&self, i: u32, hardened: bool) -> Option<&ChildWallet> {
         if !hardened {
-            self.children.get(i as usize).and_then(|a| a.as_ref())
+            self.children.get(&i)
         } else {
-            self.hardened_children
-                .get(i as usize)
-                .and_then(|a| a.as_ref())
+            self.hardened_children.get(&i)
         }
     }
 
@@ -164,86 +326,201 @@ impl ChildWallet {
         pubkey_hash_to_addr(&self.pubkey_hash(), network)
     }
 
-    fn serializable(&self) -> Result<SerializableChildWallet, Error> {
-        let seed = self.seed.clone();
-        let mpriv = self.mpriv.clone().map(|k| k.serialize());
-        let mpub = self.mpub.clone().map(|k| k.serialize());
-        let children: Vec<Option<ByteVec>> = self
-            .children
-            .iter()
-            .map(|c| match c {
-                Some(c) => Ok(Some(ByteVec(c.as_bytes()?))),
-                None => Ok(None),
-            })
-            .collect::<Result<Vec<Option<ByteVec>>, Error>>()?;
-
-        let hardened_children: Vec<Option<ByteVec>> = self
-            .hardened_children
-            .iter()
-            .map(|c| match c {
-                Some(c) => Ok(Some(ByteVec(c.as_bytes()?))),
-                None => Ok(None),
-            })
-            .collect::<Result<Vec<Option<ByteVec>>, Error>>()?;
-
-        Ok(SerializableChildWallet {
-            seed,
-            mpriv,
-            mpub,
-            children,
-            hardened_children,
-            nonce: self.nonce,
-        })
+    /// Bech32 P2WPKH address. Dogecoin has no segwit.
+    pub fn segwit_address(&self, network: Network) -> Result<String, Error> {
+        pubkey_hash_to_segwit_addr(&self.pubkey_hash(), network)
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    pub fn parent_fingerprint(&self) -> [u8; 4] {
+        self.parent_fingerprint
+    }
+
+    pub fn child_number(&self) -> u32 {
+        self.child_number
+    }
+
+    /// Path of this wallet relative to the root it was derived from.
+    pub fn path(&self) -> &DerivationPath {
+        &self.path
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn set_label(&mut self, label: Option<String>) {
+        self.label = label;
+    }
+
+    pub fn fingerprint(&self) -> [u8; 4] {
+        let mut res: [u8; 4] = [0; 4];
+        res.clone_from_slice(&self.pubkey_hash()[0..4]);
+        res
+    }
+
+    /// WIF export of this wallet's private key (always compressed).
+    pub fn to_wif(&self, network: Network) -> Result<String, Error> {
+        Ok(key::to_wif(self.secret_key()?, true, network))
+    }
+
+    fn extended_key(&self, version: [u8; 4], key: &[u8]) -> String {
+        let mut data: Vec<u8> = Vec::with_capacity(78);
+        data.extend(&version);
+        data.push(self.depth);
+        data.extend(&self.parent_fingerprint);
+        data.extend(&self.child_number.to_be_bytes());
+        data.extend(self.chain_code());
+        data.extend(key);
+
+        bitcoin::util::base58::check_encode_slice(&data)
+    }
+
+    /// BIP32 extended public key (xpub, Ltub or dgub depending on network)
+    pub fn to_xpub(&self, network: Network) -> String {
+        self.extended_key(xpub_version(network), &self.mpub().serialize_compressed())
+    }
+
+    /// BIP32 extended private key (xprv, Ltpv or dgpv depending on network)
+    pub fn to_xprv(&self, network: Network) -> Result<String, Error> {
+        let mpriv = self
+            .mpriv
+            .as_ref()
+            .ok_or(err!(Wallet, "wallet has no private key"))?;
+        let key = [&[0x00], &mpriv.serialize()[..]].concat();
+        Ok(self.extended_key(xprv_version(network), &key))
+    }
+
+    fn collect_entries(&self, path: &[u32], entries: &mut Vec<PathEntry>) {
+        entries.push(PathEntry {
+            path: path.to_vec(),
+            nonce: self.nonces.next(),
+            open_nonces: self.nonces.open().to_vec(),
+            label: self.label.clone(),
+        });
+        for (i, child) in self.children.iter() {
+            child.collect_entries(&[path, &[*i]].concat(), entries);
+        }
+        for (i, child) in self.hardened_children.iter() {
+            child.collect_entries(&[path, &[*i | HARDENED_BIT]].concat(), entries);
+        }
+    }
+
+    /// Every wallet derived from this one (including itself), relative to it.
+    pub(super) fn entries(&self) -> Vec<PathEntry> {
+        let mut entries = Vec::new();
+        self.collect_entries(&[], &mut entries);
+        entries
+    }
+
+    pub(super) fn restore_entries(&mut self, entries: Vec<PathEntry>) -> Result<(), Error> {
+        for entry in entries {
+            let child = self.derive(&DerivationPath::new(
+                entry.path.into_iter().map(ChildNumber::from_u32).collect(),
+            ))?;
+            child.nonces = NonceAllocator::from_parts(entry.nonce, entry.open_nonces);
+            child.label = entry.label;
+        }
+        Ok(())
+    }
+
+    fn serializable(&self) -> ChildWalletFile {
+        ChildWalletFile {
+            version: WALLET_FILE_VERSION,
+            secret_key: self.mpriv.as_ref().map(|k| ByteVec(k.serialize().to_vec())),
+            public_key: ByteVec(self.mpub().serialize_compressed().to_vec()),
+            chain_code: ByteVec(self.chain_code().to_vec()),
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            path: self.path.children().iter().map(|n| n.to_u32()).collect(),
+            wallets: self.entries(),
+        }
+    }
+
+    fn from_serializable(w: ChildWalletFile) -> Result<Self, Error> {
+        ensure!(
+            w.version == WALLET_FILE_VERSION,
+            Decode,
+            "unsupported wallet file version {}",
+            w.version
+        );
+        ensure!(
+            w.chain_code.0.len() == 32,
+            Decode,
+            "invalid chain code length"
+        );
+
+        let mut res = match w.secret_key {
+            Some(ByteVec(ref key)) => {
+                ensure!(key.len() == 32, Decode, "invalid private key length");
+                let mut seed: [u8; 64] = [0; 64];
+                seed[0..32].clone_from_slice(key);
+                seed[32..64].clone_from_slice(&w.chain_code.0);
+                ChildWallet::new(seed)?
+            }
+            None => ChildWallet::from_public(
+                PublicKey::parse_slice(&w.public_key.0, Some(PublicKeyFormat::Compressed))
+                    .map_err(|e| err!(Decode, "{:?}", e))?,
+                &w.chain_code.0,
+            ),
+        };
+        res.depth = w.depth;
+        res.parent_fingerprint = w.parent_fingerprint;
+        res.child_number = w.child_number;
+        res.path = DerivationPath::new(w.path.into_iter().map(ChildNumber::from_u32).collect());
+        res.restore_entries(w.wallets)?;
+        Ok(res)
     }
 
-    fn from_serializable(w: SerializableChildWallet) -> Result<Self, Error> {
+    fn from_legacy(w: LegacyChildWallet) -> Result<Self, Error> {
         let seed = w.seed;
 
         let mpriv = match w.mpriv {
-            Some(data) => Some(SecretKey::parse(&data).map_err(|e| format_err!("{:?}", e))?),
+            Some(data) => Some(SecretKey::parse(&data).map_err(|e| err!(Decode, "{:?}", e))?),
             None => None,
         };
 
         let mpub = match w.mpub {
-            Some(data) => Some(PublicKey::parse(&data).map_err(|e| format_err!("{:?}", e))?),
+            Some(data) => Some(PublicKey::parse(&data).map_err(|e| err!(Decode, "{:?}", e))?),
             None => None,
         };
 
-        let children: Vec<Option<ChildWallet>> = w
-            .children
-            .iter()
-            .map(|c| match c {
-                Some(ByteVec(ref c)) => Ok(Some(ChildWallet::from_bytes(c)?)),
-                None => Ok(None),
-            })
-            .collect::<Result<Vec<Option<ChildWallet>>, Error>>()?;
-
-        let hardened_children: Vec<Option<ChildWallet>> = w
-            .hardened_children
-            .iter()
-            .map(|c| match c {
-                Some(ByteVec(ref c)) => Ok(Some(ChildWallet::from_bytes(c)?)),
-                None => Ok(None),
-            })
-            .collect::<Result<Vec<Option<ChildWallet>>, Error>>()?;
-
-        Ok(ChildWallet {
+        let children = w.children.into_wallets()?;
+        let hardened_children = w.hardened_children.into_wallets()?;
+
+        let mut res = ChildWallet {
             seed,
             mpriv,
             mpub,
             children,
             hardened_children,
-            nonce: w.nonce,
-        })
+            nonces: NonceAllocator::new(w.nonce),
+            depth: w.depth,
+            parent_fingerprint: w.parent_fingerprint,
+            child_number: w.child_number,
+            path: DerivationPath::default(),
+            label: None,
+        };
+        res.path = DerivationPath::new(w.path.into_iter().map(ChildNumber::from_u32).collect());
+        res.relink();
+        Ok(res)
     }
 
     pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
-        Ok(serde_cbor::to_vec(&self.serializable()?)?)
+        Ok(serde_cbor::to_vec(&self.serializable())?)
     }
 
+    /// Reads the current wallet file format, falling back to the legacy
+    /// nested layout.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        let w: SerializableChildWallet = serde_cbor::from_slice(bytes)?;
-        Self::from_serializable(w)
+        match serde_cbor::from_slice::<ChildWalletFile>(bytes) {
+            Ok(w) => Self::from_serializable(w),
+            Err(_) => Self::from_legacy(serde_cbor::from_slice(bytes)?),
+        }
     }
 
     pub fn script(&self) -> bitcoin::Script {
@@ -257,122 +534,302 @@ impl ChildWallet {
         fee: u64,
         op_return: Option<&[u8]>,
     ) -> Result<Vec<u8>, Error> {
-        use bitcoin::consensus::Decodable;
-        use bitcoin::consensus::Encodable;
-        use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
-        use bitcoin_hashes::Hash;
-        use std::io::Cursor;
-
-        let script = self.script();
-
-        let inputs = inputs
-            .into_iter()
-            .map(|i| Transaction::consensus_decode(&mut Cursor::new(i)))
-            .collect::<Result<Vec<_>, _>>()?
-            .into_iter()
-            .flat_map(|tx| {
-                tx.output
-                    .clone()
-                    .into_iter()
-                    .enumerate()
-                    .filter(|(_, o)| o.script_pubkey == script)
-                    .map(|(vout, o)| {
-                        (
-                            OutPoint {
-                                txid: tx.txid(),
-                                vout: vout as u32,
-                            },
-                            o,
-                        )
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .collect::<Vec<_>>();
-        let input_size = inputs.iter().fold(0, |acc, tx| acc + tx.1.value);
-        let output_size = outputs.iter().fold(0, |acc, o| acc + o.1);
-        if output_size > input_size - fee || input_size < fee {
-            bail!("insufficient funds")
-        }
-        let mut outputs = outputs.iter().cloned().collect::<Vec<_>>();
-        let address = self.address(Network::Bitcoin);
-        outputs.push((address.as_str(), input_size - output_size - fee));
-
-        let output = outputs
-            .into_iter()
-            .map(|(addr, val)| -> Result<_, Error> {
-                Ok(TxOut {
-                    script_pubkey: addr_to_script(addr)?,
-                    value: val,
-                })
-            })
-            .chain(op_return.into_iter().map(|data| -> Result<_, Error> {
-                let mut s: Vec<u8> = vec![0x6a, 0x4c, data.len() as u8];
-                s.extend(data.iter());
-                Ok(TxOut {
-                    script_pubkey: bitcoin::Script::from(s),
-                    value: 0,
-                })
-            }))
-            .collect::<Result<Vec<_>, Error>>()?;
-        let input: Vec<TxIn> = inputs
-            .into_iter()
-            .map(|a| TxIn {
-                previous_output: a.0,
-                script_sig: bitcoin::Script::new(),
-                sequence: 0xFFFFFFFF_u32,
-                witness: vec![],
-            })
-            .collect();
-        let mut tx = Transaction {
-            version: 1,
-            lock_time: 0,
-            input: input.clone(),
-            output,
-        };
-        tx.input = input
-            .into_iter()
-            .enumerate()
-            .map(|(i, vin)| -> Result<_, Error> {
-                let sighash = tx.signature_hash(i, &script, 0x01).into_inner();
-                let sig = secp256k1::sign(&secp256k1::Message::parse(&sighash), self.mpriv())
-                    .map_err(|e| format_err!("{:?}", e))?
-                    .0;
-                let pubkey = self.mpub().serialize_compressed();
-                let sig_der = sig.serialize_der();
-                let script_sig = bitcoin::Script::from(
-                    [
-                        &[sig_der.as_ref().len() as u8 + 1][..],
-                        sig_der.as_ref(),
-                        &[0x01, pubkey.len() as u8][..],
-                        &pubkey[..],
-                    ]
-                    .concat(),
-                );
-                Ok(TxIn {
-                    previous_output: vin.previous_output,
-                    script_sig,
-                    sequence: vin.sequence,
-                    witness: vin.witness,
-                })
-            })
-            .collect::<Result<Vec<_>, Error>>()?;
-        let mut res = Vec::new();
-        tx.consensus_encode(&mut res)?;
-        Ok(res)
+        tx::construct_signed(self, inputs, outputs, fee, op_return)
+    }
+
+    pub fn sign_message(&self, message: &str, network: Network) -> Result<String, Error> {
+        message::sign_message(self, message, network)
+    }
+
+    pub fn encrypt_direct_message(
+        &self,
+        recipient_pubkey: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        direct::encrypt_direct_message(self, recipient_pubkey, plaintext)
+    }
+
+    pub fn decrypt_direct_message(
+        &self,
+        pubkey: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        direct::decrypt_direct_message(self, pubkey, ciphertext)
     }
 }
 
+impl Signer for ChildWallet {
+    fn secret_key(&self) -> Result<&SecretKey, Error> {
+        self.mpriv
+            .as_ref()
+            .ok_or(err!(Wallet, "watch-only wallet cannot sign"))
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.mpub().serialize_compressed().to_vec()
+    }
+
+    fn script(&self) -> bitcoin::Script {
+        ChildWallet::script(self)
+    }
+}
+
+pub const WALLET_FILE_VERSION: u8 = 1;
+
 #[derive(Deserialize, Serialize)]
 struct ByteVec(#[serde(with = "serde_bytes")] pub Vec<u8>);
 
 #[derive(Deserialize, Serialize)]
-pub struct SerializableChildWallet {
+pub struct PathEntry {
+    path: Vec<u32>,
+    nonce: u8,
+    #[serde(default)]
+    open_nonces: Vec<OpenNonce>,
+    label: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ChildWalletFile {
+    version: u8,
+    secret_key: Option<ByteVec>,
+    public_key: ByteVec,
+    chain_code: ByteVec,
+    depth: u8,
+    parent_fingerprint: [u8; 4],
+    child_number: u32,
+    path: Vec<u32>,
+    wallets: Vec<PathEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LegacyChildList {
+    Sparse(BTreeMap<u32, ByteVec>),
+    Dense(Vec<Option<ByteVec>>),
+}
+impl LegacyChildList {
+    fn into_wallets(self) -> Result<BTreeMap<u32, ChildWallet>, Error> {
+        let parse = |c: &[u8]| ChildWallet::from_legacy(serde_cbor::from_slice(c)?);
+        match self {
+            LegacyChildList::Sparse(children) => children
+                .into_iter()
+                .map(|(i, ByteVec(c))| Ok((i, parse(&c)?)))
+                .collect(),
+            LegacyChildList::Dense(children) => children
+                .into_iter()
+                .enumerate()
+                .filter_map(|(i, c)| c.map(|ByteVec(c)| (i as u32, c)))
+                .map(|(i, c)| Ok((i, parse(&c)?)))
+                .collect(),
+        }
+    }
+}
+
+/// Original layout, which nests every cached child with its full key material.
+#[derive(Deserialize)]
+pub struct LegacyChildWallet {
     #[serde(with = "BigArray")]
     seed: [u8; 64],
     mpriv: Option<[u8; 32]>,
     #[serde(with = "BigArray")]
     mpub: Option<[u8; 65]>,
-    children: Vec<Option<ByteVec>>,
-    hardened_children: Vec<Option<ByteVec>>,
+    children: LegacyChildList,
+    hardened_children: LegacyChildList,
     nonce: u8,
+    #[serde(default)]
+    depth: u8,
+    #[serde(default)]
+    parent_fingerprint: [u8; 4],
+    #[serde(default)]
+    child_number: u32,
+    #[serde(default)]
+    path: Vec<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Wallet;
+
+    #[test]
+    fn legacy_children_keep_their_bip32_position() {
+        // Written by the original nested wallet format, from entropy 0x5a...
+        // with m/44'/3'/0'/0/5 loaded.
+        let mut legacy = Wallet::from_bytes(include_bytes!("fixtures/legacy_wallet.cbor")).unwrap();
+        let mut fresh = Wallet::from_entropy([0x5a; 16]).unwrap();
+        let paths = [
+            "m/44'",
+            "m/44'/3'",
+            "m/44'/3'/0'",
+            "m/44'/3'/0'/0",
+            "m/44'/3'/0'/0/5",
+        ];
+        for path in &paths {
+            let migrated = legacy.parent_mut().derive_path(path).unwrap();
+            let derived = fresh.parent_mut().derive_path(path).unwrap();
+            assert_eq!(migrated.path().to_string(), *path);
+            assert_eq!(migrated.depth(), derived.depth());
+            assert_eq!(migrated.parent_fingerprint(), derived.parent_fingerprint());
+            assert_eq!(migrated.child_number(), derived.child_number());
+            assert_eq!(
+                migrated.to_xpub(Network::Dogecoin),
+                derived.to_xpub(Network::Dogecoin)
+            );
+        }
+    }
+
+    #[test]
+    fn watch_only_has_no_private_key() {
+        let wallet = ChildWallet::new([7; 64]).unwrap();
+        assert!(wallet.mpriv().is_ok());
+        let xpub = wallet.to_xpub(Network::Bitcoin);
+        let mut watch = ChildWallet::from_xpub(&xpub, Network::Bitcoin).unwrap();
+        match watch.mpriv() {
+            Err(Error::Wallet(_)) => (),
+            other => panic!("expected a wallet error, got {:?}", other),
+        }
+        assert!(watch.load_child(0, true).is_err());
+    }
+
+    #[test]
+    fn nonce_is_reserved() {
+        let mut wallet = ChildWallet::new([7; 64]).unwrap();
+        let allocated = wallet.allocate_nonce(Utc::now()).unwrap();
+        let nonce = wallet.nonce().unwrap();
+        assert_ne!(nonce, allocated);
+        let open: Vec<u8> = wallet.nonces().open().iter().map(|o| o.nonce).collect();
+        assert_eq!(open, vec![allocated, nonce]);
+    }
+
+    /// The BIP32 master key of a hex `seed`.
+    fn master(seed: &str) -> ChildWallet {
+        let mut mac = HmacSha512::new_varkey(b"Bitcoin seed").unwrap();
+        mac.input(&hex::decode(seed).unwrap());
+        let mut l = [0; 64];
+        l.clone_from_slice(&mac.result().code());
+        ChildWallet::new(l).unwrap()
+    }
+
+    /// Checks each `(path, xpub, xprv)` of a test vector chain against
+    /// private derivation from `seed`, and each step but the first against
+    /// watch-only derivation from the previous step's xpub.
+    fn check_vector(seed: &str, chain: &[(&str, &str, &str)]) {
+        let mut master = master(seed);
+        for (i, (path, xpub, xprv)) in chain.iter().enumerate() {
+            let child = master.derive_path(path).unwrap();
+            assert_eq!(child.to_xpub(Network::Bitcoin), *xpub, "{}", path);
+            assert_eq!(child.to_xprv(Network::Bitcoin).unwrap(), *xprv, "{}", path);
+
+            if i == 0 {
+                continue;
+            }
+            let n: ChildNumber = path.rsplit('/').next().unwrap().parse().unwrap();
+            let mut watch = ChildWallet::from_xpub(chain[i - 1].1, Network::Bitcoin).unwrap();
+            assert!(watch.is_watch_only());
+            let child = watch.load_child(n.index, n.hardened);
+            if n.hardened {
+                assert!(child.is_err(), "{}", path);
+            } else {
+                assert_eq!(child.unwrap().to_xpub(Network::Bitcoin), *xpub, "{}", path);
+            }
+        }
+    }
+
+    #[test]
+    fn bip32_test_vector_1() {
+        check_vector(
+            "000102030405060708090a0b0c0d0e0f",
+            &[
+                (
+                    "m",
+                    "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8",
+                    "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi",
+                ),
+                (
+                    "m/0'",
+                    "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw",
+                    "xprv9uHRZZhk6KAJC1avXpDAp4MDc3sQKNxDiPvvkX8Br5ngLNv1TxvUxt4cV1rGL5hj6KCesnDYUhd7oWgT11eZG7XnxHrnYeSvkzY7d2bhkJ7",
+                ),
+                (
+                    "m/0'/1",
+                    "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ",
+                    "xprv9wTYmMFdV23N2TdNG573QoEsfRrWKQgWeibmLntzniatZvR9BmLnvSxqu53Kw1UmYPxLgboyZQaXwTCg8MSY3H2EU4pWcQDnRnrVA1xe8fs",
+                ),
+                (
+                    "m/0'/1/2'",
+                    "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5",
+                    "xprv9z4pot5VBttmtdRTWfWQmoH1taj2axGVzFqSb8C9xaxKymcFzXBDptWmT7FwuEzG3ryjH4ktypQSAewRiNMjANTtpgP4mLTj34bhnZX7UiM",
+                ),
+                (
+                    "m/0'/1/2'/2",
+                    "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV",
+                    "xprvA2JDeKCSNNZky6uBCviVfJSKyQ1mDYahRjijr5idH2WwLsEd4Hsb2Tyh8RfQMuPh7f7RtyzTtdrbdqqsunu5Mm3wDvUAKRHSC34sJ7in334",
+                ),
+                (
+                    "m/0'/1/2'/2/1000000000",
+                    "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8yGasTvXEYBVPamhGW6cFJodrTHy",
+                    "xprvA41z7zogVVwxVSgdKUHDy1SKmdb533PjDz7J6N6mV6uS3ze1ai8FHa8kmHScGpWmj4WggLyQjgPie1rFSruoUihUZREPSL39UNdE3BBDu76",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn bip32_test_vector_2() {
+        check_vector(
+            "fffcf9f6f3f0edeae7e4e1dedbd8d5d2cfccc9c6c3c0bdbab7b4b1aeaba8a5a29f9c999693908d8a8784817e7b7875726f6c696663605d5a5754514e4b484542",
+            &[
+                (
+                    "m",
+                    "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB",
+                    "xprv9s21ZrQH143K31xYSDQpPDxsXRTUcvj2iNHm5NUtrGiGG5e2DtALGdso3pGz6ssrdK4PFmM8NSpSBHNqPqm55Qn3LqFtT2emdEXVYsCzC2U",
+                ),
+                (
+                    "m/0",
+                    "xpub69H7F5d8KSRgmmdJg2KhpAK8SR3DjMwAdkxj3ZuxV27CprR9LgpeyGmXUbC6wb7ERfvrnKZjXoUmmDznezpbZb7ap6r1D3tgFxHmwMkQTPH",
+                    "xprv9vHkqa6EV4sPZHYqZznhT2NPtPCjKuDKGY38FBWLvgaDx45zo9WQRUT3dKYnjwih2yJD9mkrocEZXo1ex8G81dwSM1fwqWpWkeS3v86pgKt",
+                ),
+                (
+                    "m/0/2147483647'",
+                    "xpub6ASAVgeehLbnwdqV6UKMHVzgqAG8Gr6riv3Fxxpj8ksbH9ebxaEyBLZ85ySDhKiLDBrQSARLq1uNRts8RuJiHjaDMBU4Zn9h8LZNnBC5y4a",
+                    "xprv9wSp6B7kry3Vj9m1zSnLvN3xH8RdsPP1Mh7fAaR7aRLcQMKTR2vidYEeEg2mUCTAwCd6vnxVrcjfy2kRgVsFawNzmjuHc2YmYRmagcEPdU9",
+                ),
+                (
+                    "m/0/2147483647'/1",
+                    "xpub6DF8uhdarytz3FWdA8TvFSvvAh8dP3283MY7p2V4SeE2wyWmG5mg5EwVvmdMVCQcoNJxGoWaU9DCWh89LojfZ537wTfunKau47EL2dhHKon",
+                    "xprv9zFnWC6h2cLgpmSA46vutJzBcfJ8yaJGg8cX1e5StJh45BBciYTRXSd25UEPVuesF9yog62tGAQtHjXajPPdbRCHuWS6T8XA2ECKADdw4Ef",
+                ),
+                (
+                    "m/0/2147483647'/1/2147483646'",
+                    "xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL",
+                    "xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc",
+                ),
+                (
+                    "m/0/2147483647'/1/2147483646'/2",
+                    "xpub6FnCn6nSzZAw5Tw7cgR9bi15UV96gLZhjDstkXXxvCLsUXBGXPdSnLFbdpq8p9HmGsApME5hQTZ3emM2rnY5agb9rXpVGyy3bdW6EEgAtqt",
+                    "xprvA2nrNbFZABcdryreWet9Ea4LvTJcGsqrMzxHx98MMrotbir7yrKCEXw7nadnHM8Dq38EGfSh6dqA9QWTyefMLEcBYJUuekgW4BYPJcr9E7j",
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn bip32_test_vector_3() {
+        // Retention of leading zeros in the private key.
+        check_vector(
+            "4b381541583be4423346c643850da4b320e46a87ae3d2a4e6da11eba819cd4acba45d239319ac14f863b8d5ab5a0d0c64d2e8a1e7d1457df2e5a3c51c73235be",
+            &[
+                (
+                    "m",
+                    "xpub661MyMwAqRbcEZVB4dScxMAdx6d4nFc9nvyvH3v4gJL378CSRZiYmhRoP7mBy6gSPSCYk6SzXPTf3ND1cZAceL7SfJ1Z3GC8vBgp2epUt13",
+                    "xprv9s21ZrQH143K25QhxbucbDDuQ4naNntJRi4KUfWT7xo4EKsHt2QJDu7KXp1A3u7Bi1j8ph3EGsZ9Xvz9dGuVrtHHs7pXeTzjuxBrCmmhgC6",
+                ),
+                (
+                    "m/0'",
+                    "xpub68NZiKmJWnxxS6aaHmn81bvJeTESw724CRDs6HbuccFQN9Ku14VQrADWgqbhhTHBaohPX4CjNLf9fq9MYo6oDaPPLPxSb7gwQN3ih19Zm4Y",
+                    "xprv9uPDJpEQgRQfDcW7BkF7eTya6RPxXeJCqCJGHuCJ4GiRVLzkTXBAJMu2qaMWPrS7AANYqdq6vcBcBUdJCVVFceUvJFjaPdGZ2y9WACViL4L",
+                ),
+            ],
+        );
+    }
 }
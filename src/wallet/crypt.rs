@@ -0,0 +1,212 @@
+use crate::Error;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// The most expensive scrypt parameters accepted, so that a crafted wallet
+/// file cannot make opening it take unbounded memory or time. Scrypt needs
+/// 128 * r * 2^log_n bytes, so at most 2 GiB here and 1 GiB for r = 8.
+pub const MAX_LOG_N: u8 = 20;
+pub const MAX_R_TIMES_P: u64 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Header {
+    version: u8,
+    kdf: KdfParams,
+    #[serde(with = "serde_bytes")]
+    salt: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    nonce: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Envelope {
+    header: Header,
+    #[serde(with = "serde_bytes")]
+    ciphertext: Vec<u8>,
+}
+
+fn within_limits(kdf: KdfParams) -> bool {
+    kdf.log_n <= MAX_LOG_N && u64::from(kdf.r) * u64::from(kdf.p) <= MAX_R_TIMES_P
+}
+
+fn derive_key(password: &str, salt: &[u8], kdf: KdfParams) -> Result<Key, Error> {
+    let params = scrypt::ScryptParams::new(kdf.log_n, kdf.r, kdf.p)
+        .map_err(|_| err!(Encode, "invalid scrypt parameters"))?;
+    let mut key = Key::default();
+    scrypt::scrypt(password.as_bytes(), salt, &params, &mut key)
+        .map_err(|_| err!(Encode, "invalid scrypt output length"))?;
+    Ok(key)
+}
+
+/// Encrypts `data` with a key derived from `password` (scrypt +
+/// XChaCha20-Poly1305). The header, including KDF parameters, is
+/// authenticated as associated data.
+pub fn seal(data: &[u8], password: &str, kdf: KdfParams) -> Result<Vec<u8>, Error> {
+    use rand::rngs::EntropyRng;
+    use rand::RngCore;
+
+    let mut rng = EntropyRng::new();
+    let mut salt = vec![0; 16];
+    rng.fill_bytes(&mut salt);
+    let mut nonce = [0; 24];
+    rng.fill_bytes(&mut nonce);
+
+    ensure!(
+        within_limits(kdf),
+        Encode,
+        "scrypt parameters {:?} exceed the maximum",
+        kdf
+    );
+    let header = Header {
+        version: ENVELOPE_VERSION,
+        kdf,
+        salt,
+        nonce: nonce.to_vec(),
+    };
+    let aad = serde_cbor::to_vec(&header)?;
+    let cipher = XChaCha20Poly1305::new(&derive_key(password, &header.salt, kdf)?);
+    let ciphertext = cipher
+        .encrypt(
+            &XNonce::from(nonce),
+            Payload {
+                msg: data,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| err!(Encode, "encryption failed"))?;
+
+    Ok(serde_cbor::to_vec(&Envelope { header, ciphertext })?)
+}
+
+pub fn open(bytes: &[u8], password: &str) -> Result<Vec<u8>, Error> {
+    let envelope: Envelope = serde_cbor::from_slice(bytes)?;
+    let header = envelope.header;
+    ensure!(
+        header.version == ENVELOPE_VERSION,
+        Decode,
+        "unsupported encrypted wallet version {}",
+        header.version
+    );
+    ensure!(
+        within_limits(header.kdf),
+        Decode,
+        "scrypt parameters {:?} exceed the maximum",
+        header.kdf
+    );
+    let nonce: [u8; 24] = header
+        .nonce
+        .as_slice()
+        .try_into()
+        .map_err(|_| err!(Decode, "invalid nonce length"))?;
+
+    let aad = serde_cbor::to_vec(&header)?;
+    let cipher = XChaCha20Poly1305::new(&derive_key(password, &header.salt, header.kdf)?);
+    cipher
+        .decrypt(
+            &XNonce::from(nonce),
+            Payload {
+                msg: &envelope.ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| err!(Wallet, "incorrect password or corrupted wallet"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Wallet;
+
+    const FAST: KdfParams = KdfParams {
+        log_n: 4,
+        r: 8,
+        p: 1,
+    };
+
+    /// An envelope claiming `kdf`, which `open` must refuse before running
+    /// scrypt with it.
+    fn envelope(kdf: KdfParams) -> Vec<u8> {
+        serde_cbor::to_vec(&Envelope {
+            header: Header {
+                version: ENVELOPE_VERSION,
+                kdf,
+                salt: vec![0; 16],
+                nonce: vec![0; 24],
+            },
+            ciphertext: vec![0; 32],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let sealed = seal(b"wallet", "hunter2", FAST).unwrap();
+        assert_eq!(open(&sealed, "hunter2").unwrap(), b"wallet");
+        match open(&sealed, "hunter3") {
+            Err(Error::Wallet(_)) => (),
+            other => panic!("expected a wallet error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_expensive_kdf_params() {
+        let params = [
+            KdfParams {
+                log_n: MAX_LOG_N + 1,
+                ..FAST
+            },
+            KdfParams { log_n: 63, ..FAST },
+            KdfParams { r: 17, ..FAST },
+            KdfParams {
+                r: u32::MAX,
+                p: u32::MAX,
+                ..FAST
+            },
+        ];
+        for kdf in &params {
+            match open(&envelope(*kdf), "hunter2") {
+                Err(Error::Decode(_)) => (),
+                other => panic!("expected {:?} to be refused, got {:?}", kdf, other),
+            }
+            match seal(b"wallet", "hunter2", *kdf) {
+                Err(Error::Encode(_)) => (),
+                other => panic!("expected {:?} to be refused, got {:?}", kdf, other),
+            }
+        }
+    }
+
+    #[test]
+    fn rekey_replaces_the_password() {
+        let wallet = Wallet::from_entropy([0x5a; 16]).unwrap();
+        let sealed = seal(&wallet.as_bytes().unwrap(), "hunter2", FAST).unwrap();
+        let rekeyed = Wallet::rekey(&sealed, "hunter2", "correct horse").unwrap();
+
+        match Wallet::from_encrypted_bytes(&rekeyed, "hunter2") {
+            Err(Error::Wallet(_)) => (),
+            other => panic!("expected a wallet error, got {:?}", other.map(|_| ())),
+        }
+        let opened = Wallet::from_encrypted_bytes(&rekeyed, "correct horse").unwrap();
+        assert_eq!(opened.as_bytes().unwrap(), wallet.as_bytes().unwrap());
+        assert!(Wallet::rekey(&sealed, "hunter3", "correct horse").is_err());
+    }
+}
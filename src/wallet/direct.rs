@@ -0,0 +1,97 @@
+use super::tx::Signer;
+use crate::Error;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use secp256k1::{PublicKey, SharedSecret};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+
+const NONCE_LEN: usize = 24;
+
+/// The key shared by `signer` and the holder of `pubkey`: the SHA256 of a
+/// fixed label and the ECDH secret of their two static keys. Sender and
+/// recipient derive the same one, so either can decrypt. There is no
+/// ephemeral key, so every message between the same two keys uses the same
+/// key and there is no forward secrecy: whoever learns either private key
+/// can read all of them.
+fn shared_key<S: Signer + ?Sized>(signer: &S, pubkey: &[u8]) -> Result<Key, Error> {
+    let pubkey =
+        PublicKey::parse_slice(pubkey, None).map_err(|_| err!(Decode, "invalid public key"))?;
+    let shared = SharedSecret::new(&pubkey, signer.secret_key()?)?;
+    let mut hasher = Sha256::new();
+    hasher.input(b"borker direct message");
+    hasher.input(shared.as_ref());
+    let mut key = [0; 32];
+    key.copy_from_slice(&hasher.result());
+    Ok(Key::from(key))
+}
+
+/// Encrypts `plaintext` to `recipient_pubkey` with XChaCha20-Poly1305, keyed
+/// by `shared_key`. The result is the random nonce followed by the
+/// ciphertext, ready for a `DirectMessage`.
+pub fn encrypt_direct_message<S: Signer + ?Sized>(
+    signer: &S,
+    recipient_pubkey: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    use rand::rngs::EntropyRng;
+    use rand::RngCore;
+
+    let mut nonce = [0; NONCE_LEN];
+    EntropyRng::new().fill_bytes(&mut nonce);
+    let cipher = XChaCha20Poly1305::new(&shared_key(signer, recipient_pubkey)?);
+    let ciphertext = cipher
+        .encrypt(&XNonce::from(nonce), plaintext)
+        .map_err(|_| err!(Encode, "encryption failed"))?;
+    let mut res = nonce.to_vec();
+    res.extend(ciphertext);
+    Ok(res)
+}
+
+/// Decrypts a direct message exchanged with the holder of `pubkey`, which is
+/// the sender's key for a received message and the recipient's for a sent
+/// one.
+pub fn decrypt_direct_message<S: Signer + ?Sized>(
+    signer: &S,
+    pubkey: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let nonce: [u8; NONCE_LEN] = ciphertext
+        .get(..NONCE_LEN)
+        .and_then(|n| n.try_into().ok())
+        .ok_or(err!(Decode, "direct message is too short"))?;
+    let cipher = XChaCha20Poly1305::new(&shared_key(signer, pubkey)?);
+    cipher
+        .decrypt(&XNonce::from(nonce), &ciphertext[NONCE_LEN..])
+        .map_err(|_| err!(Wallet, "direct message was not encrypted for this key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KeyWallet;
+
+    fn key(b: u8) -> KeyWallet {
+        KeyWallet::new(secp256k1::SecretKey::parse(&[b; 32]).unwrap(), true)
+    }
+
+    #[test]
+    fn sender_and_recipient_can_decrypt() {
+        let (alice, bob, eve) = (key(1), key(2), key(3));
+        let sealed = encrypt_direct_message(&alice, &bob.public_key(), b"woof").unwrap();
+        assert_eq!(sealed.len(), NONCE_LEN + 4 + 16);
+        assert_eq!(
+            decrypt_direct_message(&bob, &alice.public_key(), &sealed).unwrap(),
+            b"woof"
+        );
+        assert_eq!(
+            decrypt_direct_message(&alice, &bob.public_key(), &sealed).unwrap(),
+            b"woof"
+        );
+        assert!(decrypt_direct_message(&eve, &alice.public_key(), &sealed).is_err());
+        match decrypt_direct_message(&bob, &alice.public_key(), &sealed[..NONCE_LEN - 1]) {
+            Err(Error::Decode(_)) => (),
+            other => panic!("expected a decode error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use super::path::Chain;
+use super::{ChildWallet, Wallet};
+use crate::Error;
+use crate::Network;
+use std::collections::HashSet;
+
+/// Source of address usage, such as an indexer or a local set of known
+/// addresses.
+pub trait AddressHistory {
+    /// Returns, for each address in order, whether it has ever received funds.
+    fn has_history(&mut self, addresses: &[String]) -> Result<Vec<bool>, Error>;
+}
+impl<F> AddressHistory for F
+where
+    F: FnMut(&[String]) -> Result<Vec<bool>, Error>,
+{
+    fn has_history(&mut self, addresses: &[String]) -> Result<Vec<bool>, Error> {
+        self(addresses)
+    }
+}
+impl AddressHistory for HashSet<String> {
+    fn has_history(&mut self, addresses: &[String]) -> Result<Vec<bool>, Error> {
+        Ok(addresses.iter().map(|a| self.contains(a)).collect())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DiscoveryOptions {
+    pub gap_limit: u32,
+    pub batch_size: u32,
+}
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        DiscoveryOptions {
+            gap_limit: 20,
+            batch_size: 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccountUsage {
+    pub receive: Vec<u32>,
+    pub change: Vec<u32>,
+}
+impl AccountUsage {
+    pub fn is_empty(&self) -> bool {
+        self.receive.is_empty() && self.change.is_empty()
+    }
+}
+
+/// Scans a chain wallet (`.../0` or `.../1`) in batches, returning the used
+/// indices once `gap_limit` consecutive unused keys have been seen. A key is
+/// used if either its P2PKH or, where the network has one, its bech32
+/// address has history.
+pub fn discover_chain<H: AddressHistory>(
+    chain: &mut ChildWallet,
+    network: Network,
+    history: &mut H,
+    options: DiscoveryOptions,
+) -> Result<Vec<u32>, Error> {
+    ensure!(options.gap_limit > 0, Wallet, "gap limit must be positive");
+    let batch_size = options.batch_size.max(1);
+
+    let mut used = Vec::new();
+    let mut next: u32 = 0;
+    let mut gap: u32 = 0;
+    while gap < options.gap_limit {
+        let mut idxs = Vec::new();
+        let mut addresses = Vec::new();
+        for i in next..next + batch_size {
+            match chain.load_child(i, false) {
+                Ok(child) => {
+                    idxs.push(i);
+                    addresses.push(child.address(network));
+                    if let Ok(segwit) = child.segwit_address(network) {
+                        idxs.push(i);
+                        addresses.push(segwit);
+                    }
+                }
+                Err(Error::InvalidChild(_)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        let res = history.has_history(&addresses)?;
+        ensure!(
+            res.len() == addresses.len(),
+            Wallet,
+            "address history returned {} results for {} addresses",
+            res.len(),
+            addresses.len()
+        );
+        let mut keys: Vec<(u32, bool)> = Vec::new();
+        for (i, has_history) in idxs.into_iter().zip(res) {
+            match keys.last_mut() {
+                Some(key) if key.0 == i => key.1 |= has_history,
+                _ => keys.push((i, has_history)),
+            }
+        }
+        for (i, has_history) in keys {
+            if has_history {
+                used.push(i);
+                gap = 0;
+            } else {
+                gap += 1;
+            }
+        }
+        next += batch_size;
+    }
+
+    Ok(used)
+}
+
+/// Scans the external and change chains of a BIP44 account wallet, which may
+/// be watch-only.
+pub fn discover_account<H: AddressHistory>(
+    account: &mut ChildWallet,
+    network: Network,
+    history: &mut H,
+    options: DiscoveryOptions,
+) -> Result<AccountUsage, Error> {
+    let receive = discover_chain(
+        account.load_child(Chain::External.index(), false)?,
+        network,
+        history,
+        options,
+    )?;
+    let change = discover_chain(
+        account.load_child(Chain::Change.index(), false)?,
+        network,
+        history,
+        options,
+    )?;
+    Ok(AccountUsage { receive, change })
+}
+
+impl Wallet {
+    /// BIP44 account discovery: scans accounts in order until one with no
+    /// history is found. The result is indexed by account number.
+    pub fn discover_accounts<H: AddressHistory>(
+        &mut self,
+        network: Network,
+        history: &mut H,
+        options: DiscoveryOptions,
+    ) -> Result<Vec<AccountUsage>, Error> {
+        let mut res = Vec::new();
+        for account in 0.. {
+            let usage = discover_account(
+                self.bip44_account(network, account)?,
+                network,
+                history,
+                options,
+            )?;
+            if usage.is_empty() {
+                break;
+            }
+            res.push(usage);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_keys_used_only_on_segwit_addresses() {
+        let mut chain = ChildWallet::new([7; 64]).unwrap();
+        let mut history = HashSet::new();
+        for i in &[2, 5] {
+            let child = chain.load_child(*i, false).unwrap();
+            history.insert(child.segwit_address(Network::Bitcoin).unwrap());
+        }
+        let options = DiscoveryOptions {
+            gap_limit: 4,
+            batch_size: 3,
+        };
+        let used = discover_chain(&mut chain, Network::Bitcoin, &mut history, options).unwrap();
+        assert_eq!(used, vec![2, 5]);
+    }
+}
@@ -0,0 +1,238 @@
+use super::message;
+use super::tx::{self, Signer};
+use super::{
+    addr_to_script, hash160, pubkey_hash_to_segwit_addr, pubkey_to_addr, wif_version, ChildWallet,
+};
+use crate::Error;
+use crate::Network;
+use secp256k1::{PublicKey, SecretKey};
+
+pub fn to_wif(key: &SecretKey, compressed: bool, network: Network) -> String {
+    let mut data = vec![wif_version(network)];
+    data.extend(&key.serialize());
+    if compressed {
+        data.push(0x01);
+    }
+    bitcoin::util::base58::check_encode_slice(&data)
+}
+
+/// Decodes a WIF private key, returning the key and whether its public key
+/// is compressed.
+pub fn from_wif(wif: &str, network: Network) -> Result<(SecretKey, bool), Error> {
+    let data = bitcoin::util::base58::from_check(wif)?;
+    let compressed = match data.len() {
+        33 => false,
+        34 if data[33] == 0x01 => true,
+        _ => bail!(Decode, "invalid WIF length"),
+    };
+    ensure!(
+        data[0] == wif_version(network),
+        Network,
+        "WIF version does not match network"
+    );
+    let key = SecretKey::parse_slice(&data[1..33]).map_err(|e| err!(Decode, "{:?}", e))?;
+    Ok((key, compressed))
+}
+
+/// Signs a transaction moving everything held by the WIF key `wif` in
+/// `inputs` to `to`, paying `fee_rate` per byte.
+pub fn sweep(
+    wif: &str,
+    network: Network,
+    inputs: &[Vec<u8>],
+    to: &ChildWallet,
+    fee_rate: u64,
+) -> Result<Vec<u8>, Error> {
+    KeyWallet::from_wif(wif, network)?.sweep(inputs, to, fee_rate)
+}
+
+/// A single imported private key, such as a paper wallet. It can sign but
+/// has no chain code to derive children from.
+#[derive(Clone)]
+pub struct KeyWallet {
+    mpriv: SecretKey,
+    mpub: PublicKey,
+    compressed: bool,
+}
+impl KeyWallet {
+    pub fn new(mpriv: SecretKey, compressed: bool) -> Self {
+        KeyWallet {
+            mpub: PublicKey::from_secret_key(&mpriv),
+            mpriv,
+            compressed,
+        }
+    }
+
+    pub fn from_wif(wif: &str, network: Network) -> Result<Self, Error> {
+        let (mpriv, compressed) = from_wif(wif, network)?;
+        Ok(Self::new(mpriv, compressed))
+    }
+
+    pub fn to_wif(&self, network: Network) -> String {
+        to_wif(&self.mpriv, self.compressed, network)
+    }
+
+    pub fn mpriv(&self) -> &SecretKey {
+        &self.mpriv
+    }
+
+    pub fn mpub(&self) -> &PublicKey {
+        &self.mpub
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    pub fn pubkey_bytes(&self) -> Vec<u8> {
+        if self.compressed {
+            self.mpub.serialize_compressed().to_vec()
+        } else {
+            self.mpub.serialize().to_vec()
+        }
+    }
+
+    pub fn address(&self, network: Network) -> String {
+        pubkey_to_addr(&self.pubkey_bytes(), network)
+    }
+
+    /// Bech32 P2WPKH address. Only compressed keys have one.
+    pub fn segwit_address(&self, network: Network) -> Result<String, Error> {
+        ensure!(
+            self.compressed,
+            Address,
+            "uncompressed keys cannot use segwit"
+        );
+        pubkey_hash_to_segwit_addr(&hash160(&self.pubkey_bytes()), network)
+    }
+
+    pub fn script(&self) -> bitcoin::Script {
+        addr_to_script(&self.address(Network::Bitcoin)).unwrap()
+    }
+
+    pub fn construct_signed(
+        &self,
+        inputs: &[Vec<u8>],
+        outputs: &[(&str, u64)],
+        fee: u64,
+        op_return: Option<&[u8]>,
+    ) -> Result<Vec<u8>, Error> {
+        tx::construct_signed(self, inputs, outputs, fee, op_return)
+    }
+
+    /// Moves all funds in `inputs` held by this key to `to`'s address.
+    pub fn sweep(
+        &self,
+        inputs: &[Vec<u8>],
+        to: &ChildWallet,
+        fee_rate: u64,
+    ) -> Result<Vec<u8>, Error> {
+        tx::sweep(self, inputs, to.script(), fee_rate)
+    }
+
+    pub fn sign_message(&self, message: &str, network: Network) -> Result<String, Error> {
+        message::sign_message(self, message, network)
+    }
+}
+
+impl Signer for KeyWallet {
+    fn secret_key(&self) -> Result<&SecretKey, Error> {
+        Ok(&self.mpriv)
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.pubkey_bytes()
+    }
+
+    fn script(&self) -> bitcoin::Script {
+        KeyWallet::script(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> SecretKey {
+        SecretKey::parse(&[byte; 32]).unwrap()
+    }
+
+    #[test]
+    fn wif_round_trips() {
+        for network in &[Network::Dogecoin, Network::Litecoin, Network::Bitcoin] {
+            for compressed in &[true, false] {
+                let wif = to_wif(&key(1), *compressed, *network);
+                let (decoded, was_compressed) = from_wif(&wif, *network).unwrap();
+                assert_eq!(decoded.serialize(), key(1).serialize());
+                assert_eq!(was_compressed, *compressed);
+                let wallet = KeyWallet::from_wif(&wif, *network).unwrap();
+                assert_eq!(wallet.to_wif(*network), wif);
+            }
+        }
+    }
+
+    #[test]
+    fn wif_matches_known_encoding() {
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(
+            &hex::decode("0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d")
+                .unwrap(),
+        );
+        let key = SecretKey::parse(&bytes).unwrap();
+        assert_eq!(
+            to_wif(&key, false, Network::Bitcoin),
+            "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ"
+        );
+        assert_eq!(
+            to_wif(&key, true, Network::Bitcoin),
+            "KwdMAjGmerYanjeui5SHS7JkmpZvVipYvB2LJGU1ZxJwYvP98617"
+        );
+    }
+
+    #[test]
+    fn wif_for_another_network_is_refused() {
+        let wif = to_wif(&key(1), true, Network::Bitcoin);
+        match from_wif(&wif, Network::Dogecoin) {
+            Err(Error::Network(_)) => (),
+            other => panic!("expected a network error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    fn funding(output: Vec<bitcoin::TxOut>) -> Vec<u8> {
+        bitcoin::consensus::serialize(&bitcoin::Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output,
+        })
+    }
+
+    #[test]
+    fn sweep_pays_everything_but_the_fee_to_one_output() {
+        let to = ChildWallet::new([7; 64]).unwrap();
+        for compressed in &[true, false] {
+            let wallet = KeyWallet::new(key(1), *compressed);
+            let mut prevouts = vec![bitcoin::TxOut {
+                value: 40_000,
+                script_pubkey: wallet.script(),
+            }];
+            if *compressed {
+                prevouts.push(bitcoin::TxOut {
+                    value: 60_000,
+                    script_pubkey: wallet.witness_script().unwrap(),
+                });
+            }
+            let inputs = [funding(prevouts.clone())];
+            let wif = wallet.to_wif(Network::Bitcoin);
+            let tx = sweep(&wif, Network::Bitcoin, &inputs, &to, 2).unwrap();
+            let tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&tx).unwrap();
+
+            let total: u64 = prevouts.iter().map(|o| o.value).sum();
+            let fee = tx::estimate_vsize(&prevouts, 1, *compressed) * 2;
+            assert_eq!(tx.input.len(), prevouts.len());
+            assert_eq!(tx.output.len(), 1);
+            assert_eq!(tx.output[0].script_pubkey, to.script());
+            assert_eq!(tx.output[0].value, total - fee);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use super::pubkey_to_addr;
+use super::tx::Signer;
+use crate::Error;
+use crate::Network;
+use secp256k1::{Message, RecoveryId, Signature};
+
+pub fn message_magic(network: Network) -> &'static str {
+    match network {
+        Network::Dogecoin => "Dogecoin Signed Message:\n",
+        Network::Litecoin => "Litecoin Signed Message:\n",
+        Network::Bitcoin => "Bitcoin Signed Message:\n",
+    }
+}
+
+/// Double SHA256 of the magic and message, each prefixed with its length as
+/// a varint.
+pub fn message_hash(message: &str, network: Network) -> [u8; 32] {
+    use bitcoin::consensus::Encodable;
+    use bitcoin::VarInt;
+    use bitcoin_hashes::{sha256d, Hash};
+
+    let magic = message_magic(network);
+    let mut data = Vec::new();
+    for part in &[magic, message] {
+        VarInt(part.len() as u64)
+            .consensus_encode(&mut data)
+            .expect("writing to a vec cannot fail");
+        data.extend(part.as_bytes());
+    }
+    sha256d::Hash::hash(&data).into_inner()
+}
+
+/// Signs `message` with a recoverable compact signature, returned as base64.
+pub fn sign_message<S: Signer + ?Sized>(
+    signer: &S,
+    message: &str,
+    network: Network,
+) -> Result<String, Error> {
+    let hash = Message::parse(&message_hash(message, network));
+    let (sig, recid) = secp256k1::sign(&hash, signer.secret_key()?)?;
+    let compressed = signer.public_key().len() == 33;
+
+    let mut res = Vec::with_capacity(65);
+    res.push(27 + recid.serialize() + if compressed { 4 } else { 0 });
+    res.extend(sig.serialize().iter());
+    Ok(base64::encode(&res))
+}
+
+/// Recovers the address that produced `signature` over `message`.
+pub fn recover_message_signer(
+    signature: &str,
+    message: &str,
+    network: Network,
+) -> Result<String, Error> {
+    let sig = base64::decode(signature)?;
+    ensure!(sig.len() == 65, Decode, "invalid signature length");
+    ensure!(
+        sig[0] >= 27 && sig[0] < 35,
+        Decode,
+        "invalid signature header {}",
+        sig[0]
+    );
+    let header = sig[0] - 27;
+    let recid = RecoveryId::parse(header & 3).map_err(|e| err!(Decode, "{:?}", e))?;
+    let sig = Signature::parse_slice(&sig[1..]).map_err(|e| err!(Decode, "{:?}", e))?;
+
+    let hash = Message::parse(&message_hash(message, network));
+    let pubkey = secp256k1::recover(&hash, &sig, &recid).map_err(|e| err!(Decode, "{:?}", e))?;
+    Ok(if header & 4 != 0 {
+        pubkey_to_addr(&pubkey.serialize_compressed(), network)
+    } else {
+        pubkey_to_addr(&pubkey.serialize(), network)
+    })
+}
+
+/// Checks that `signature` over `message` was made by the key behind
+/// `address`. Malformed signatures are errors; a valid signature from
+/// another key returns `false`.
+pub fn verify_message(
+    address: &str,
+    signature: &str,
+    message: &str,
+    network: Network,
+) -> Result<bool, Error> {
+    Ok(recover_message_signer(signature, message, network)? == address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KeyWallet;
+
+    fn key_wallet(compressed: bool) -> KeyWallet {
+        KeyWallet::new(secp256k1::SecretKey::parse(&[1; 32]).unwrap(), compressed)
+    }
+
+    #[test]
+    fn signatures_verify_for_their_key_only() {
+        for network in &[Network::Dogecoin, Network::Litecoin, Network::Bitcoin] {
+            for compressed in &[true, false] {
+                let wallet = key_wallet(*compressed);
+                let address = wallet.address(*network);
+                let sig = sign_message(&wallet, "such message", *network).unwrap();
+                assert_eq!(
+                    recover_message_signer(&sig, "such message", *network).unwrap(),
+                    address
+                );
+                assert!(verify_message(&address, &sig, "such message", *network).unwrap());
+                assert!(!verify_message(&address, &sig, "such messages", *network).unwrap());
+                let other = key_wallet(!*compressed).address(*network);
+                assert!(!verify_message(&other, &sig, "such message", *network).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn matches_bitcoin_core_signatures() {
+        use bitcoin::util::base58::from_check;
+
+        // From Bitcoin Core's rpc_signmessage functional test, which uses a
+        // regtest key; message hashes do not depend on the test network.
+        let wif = "cUeKHd5orzT3mz8P9pxyREHfsWtVfgsfDjiZZBcjUBAaGk1BTj7N";
+        let address = "mpLQjfK79b7CCV4VMJWEWAj5Mpx8Up5zxB";
+        let expected = "INbVnW4e6PeRmsv2Qgu8NuopvrVjkcxob+sX8OcZG0SALhWybUjzMLPdAsXI46YZGb0KQTRii+wWIQzRpG/U+S0=";
+        let message = "This is just a test message";
+
+        let key = secp256k1::SecretKey::parse_slice(&from_check(wif).unwrap()[1..33]).unwrap();
+        let wallet = KeyWallet::new(key, true);
+        assert_eq!(
+            sign_message(&wallet, message, Network::Bitcoin).unwrap(),
+            expected
+        );
+        let signer = recover_message_signer(expected, message, Network::Bitcoin).unwrap();
+        assert_eq!(
+            from_check(&signer).unwrap()[1..],
+            from_check(address).unwrap()[1..]
+        );
+    }
+}
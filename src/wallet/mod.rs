@@ -1,11 +1,33 @@
 mod child;
 mod consts;
-
+mod crypt;
+mod direct;
+mod discovery;
+mod key;
+mod message;
+mod nonce;
+mod path;
+mod psbt;
+mod sponsor;
+mod tx;
 
 pub use self::child::ChildWallet;
+use self::child::{PathEntry, WALLET_FILE_VERSION};
+pub use self::direct::{decrypt_direct_message, encrypt_direct_message};
+pub use self::discovery::{
+    discover_account, discover_chain, AccountUsage, AddressHistory, DiscoveryOptions,
+};
+pub use self::key::{from_wif, sweep, to_wif, KeyWallet};
+pub use self::message::{recover_message_signer, sign_message, verify_message};
+pub use self::nonce::{reassembly_window, reservation_timeout, NonceAllocator, OpenNonce};
+pub use self::path::{coin_type, Bip44Path, Chain, ChildNumber, DerivationPath};
+pub use self::psbt::{extract_psbt, finalize_psbt};
+pub use self::sponsor::{complete_sponsored, construct_sponsor_request, SPONSORED_SIGHASH};
+pub use self::tx::{check_author, construct_authored, PathInputs, Signer};
+use crate::Error;
 use crate::Network;
 use base58::ToBase58;
-use failure::Error;
+use chrono::{DateTime, Utc};
 use pbkdf2::pbkdf2;
 use ripemd160::Digest;
 use ripemd160::Ripemd160;
@@ -20,7 +42,7 @@ pub struct Wallet {
     parent: Option<ChildWallet>,
 }
 impl Wallet {
-    pub fn new() -> Self {
+    pub fn new() -> Result<Self, Error> {
         use rand::rngs::EntropyRng;
         use rand::RngCore;
 
@@ -29,17 +51,17 @@ impl Wallet {
         Self::from_entropy(res)
     }
 
-    pub fn from_entropy(entropy: [u8; 16]) -> Self {
+    pub fn from_entropy(entropy: [u8; 16]) -> Result<Self, Error> {
         let mut res = Wallet {
             entropy,
             parent: None,
         };
-        res.init();
-        res
+        res.init()?;
+        Ok(res)
     }
 
-    pub fn init(&mut self) {
-        self.init_parent();
+    pub fn init(&mut self) -> Result<(), Error> {
+        self.init_parent()
     }
 
     pub fn entropy(&self) -> &[u8] {
@@ -85,7 +107,7 @@ impl Wallet {
         res
     }
 
-    fn from_idxs(idxs: [u16; 12]) -> Result<Self, failure::Error> {
+    fn from_idxs(idxs: [u16; 12]) -> Result<Self, Error> {
         let mut entropy: [u8; 16] = [0; 16];
         let mut idx = 0;
         let mut overflow: u16 = 0;
@@ -103,9 +125,10 @@ impl Wallet {
                 overflow = overflow & mask_16!(overflow_bits);
             }
         }
-        let res = Self::from_entropy(entropy);
+        let res = Self::from_entropy(entropy)?;
         ensure!(
             res.sha256sum() == overflow as u8,
+            Decode,
             "checksum verification failed"
         );
         Ok(res)
@@ -119,16 +142,16 @@ impl Wallet {
         res
     }
 
-    pub fn from_words(words: &[String]) -> Result<Self, failure::Error> {
+    pub fn from_words(words: &[String]) -> Result<Self, Error> {
         let dict_vec: Vec<&'static str> = consts::DICT.to_vec();
         let word_iter = words
             .into_iter()
             .map(|s| {
                 dict_vec
                     .binary_search(&s.as_str())
-                    .map_err(|_| format_err!("{} is not a valid bip39 word", s))
+                    .map_err(|_| err!(Decode, "{} is not a valid bip39 word", s))
             })
-            .collect::<Result<Vec<usize>, failure::Error>>()?;
+            .collect::<Result<Vec<usize>, Error>>()?;
         let mut idxs: [u16; 12] = [0; 12];
         for (idx, word_idx) in idxs.iter_mut().zip(word_iter) {
             *idx = word_idx as u16;
@@ -136,7 +159,7 @@ impl Wallet {
         Ok(Self::from_idxs(idxs)?)
     }
 
-    fn init_parent(&mut self) {
+    fn init_parent(&mut self) -> Result<(), Error> {
         match self.parent {
             Some(_) => (),
             None => {
@@ -154,9 +177,10 @@ impl Wallet {
                 mac.input(&seed);
                 let mut l: [u8; 64] = [0; 64];
                 l.clone_from_slice(mac.result().code().as_slice());
-                self.parent = Some(ChildWallet::new(l));
+                self.parent = Some(ChildWallet::new(l)?);
             }
         }
+        Ok(())
     }
 
     pub fn parent(&self) -> &ChildWallet {
@@ -167,17 +191,56 @@ impl Wallet {
         self.parent.as_mut().expect("wallet uninitialized")
     }
 
-    fn serializable(&self) -> Result<SerializableWallet, Error> {
-        Ok(SerializableWallet {
+    pub fn derive_path(&mut self, path: &str) -> Result<&mut ChildWallet, Error> {
+        self.parent_mut().derive_path(path)
+    }
+
+    pub fn bip44_account(
+        &mut self,
+        network: Network,
+        account: u32,
+    ) -> Result<&mut ChildWallet, Error> {
+        self.parent_mut()
+            .derive(&DerivationPath::bip44_account(network, account))
+    }
+
+    pub fn bip44_address(
+        &mut self,
+        network: Network,
+        account: u32,
+        chain: Chain,
+        index: u32,
+    ) -> Result<&mut ChildWallet, Error> {
+        self.parent_mut()
+            .derive(&DerivationPath::bip44(network, account, chain, index))
+    }
+
+    fn serializable(&self) -> WalletFile {
+        WalletFile {
+            version: WALLET_FILE_VERSION,
             entropy: self.entropy.to_vec(),
-            parent: match self.parent {
-                Some(ref a) => Some(ByteVec(a.as_bytes()?)),
-                None => None,
-            },
-        })
+            wallets: self.parent().entries(),
+        }
     }
 
-    fn from_serializable(w: SerializableWallet) -> Result<Self, Error> {
+    fn from_serializable(w: WalletFile) -> Result<Self, Error> {
+        ensure!(
+            w.version == WALLET_FILE_VERSION,
+            Decode,
+            "unsupported wallet file version {}",
+            w.version
+        );
+        ensure!(w.entropy.len() == 16, Decode, "invalid entropy length");
+        let mut entropy: [u8; 16] = [0; 16];
+        entropy.clone_from_slice(&w.entropy);
+
+        let mut res = Self::from_entropy(entropy)?;
+        res.parent_mut().restore_entries(w.wallets)?;
+        Ok(res)
+    }
+
+    fn from_legacy(w: LegacyWallet) -> Result<Self, Error> {
+        ensure!(w.entropy.len() == 16, Decode, "invalid entropy length");
         let mut entropy: [u8; 16] = [0; 16];
         entropy.clone_from_slice(&w.entropy);
 
@@ -186,7 +249,9 @@ impl Wallet {
             None => None,
         };
 
-        Ok(Wallet { entropy, parent })
+        let mut res = Wallet { entropy, parent };
+        res.init()?;
+        Ok(res)
     }
 
     pub fn check_ser(&self) -> Result<(), Error> {
@@ -197,31 +262,98 @@ impl Wallet {
         Ok(())
     }
 
+    /// Reserves a nonce on the wallet at `path` and hands the updated wallet
+    /// to `persist` before returning it, so a nonce is never used by a
+    /// broadcast transaction without first being saved. If `persist` fails
+    /// the reservation is rolled back.
+    pub fn reserve_nonce<F>(
+        &mut self,
+        path: &DerivationPath,
+        now: DateTime<Utc>,
+        persist: F,
+    ) -> Result<u8, Error>
+    where
+        F: FnOnce(&[u8]) -> Result<(), Error>,
+    {
+        let nonce = self.parent_mut().derive(path)?.allocate_nonce(now)?;
+        let res = self.as_bytes().and_then(|bytes| persist(&bytes));
+        if let Err(e) = res {
+            self.parent_mut().derive(path)?.release_nonce(nonce);
+            return Err(e);
+        }
+        Ok(nonce)
+    }
+
+    /// Compact versioned format: only the entropy plus the path, nonce and
+    /// label of each derived wallet in use. Keys are re-derived on load.
     pub fn as_bytes(&self) -> Result<Vec<u8>, Error> {
-        Ok(serde_cbor::to_vec(&self.serializable()?)?)
+        Ok(serde_cbor::to_vec(&self.serializable())?)
     }
 
+    /// Reads the current wallet file format, migrating the legacy layout.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        let w: SerializableWallet = serde_cbor::from_slice(bytes)?;
-        Self::from_serializable(w)
+        match serde_cbor::from_slice::<WalletFile>(bytes) {
+            Ok(w) => Self::from_serializable(w),
+            Err(_) => Self::from_legacy(serde_cbor::from_slice(bytes)?),
+        }
+    }
+
+    /// Password-encrypted form of `as_bytes`, safe to store on disk or in
+    /// browser storage.
+    pub fn as_encrypted_bytes(&self, password: &str) -> Result<Vec<u8>, Error> {
+        crypt::seal(&self.as_bytes()?, password, crypt::KdfParams::default())
+    }
+
+    pub fn from_encrypted_bytes(bytes: &[u8], password: &str) -> Result<Self, Error> {
+        Self::from_bytes(&crypt::open(bytes, password)?)
+    }
+
+    /// Re-encrypts an encrypted wallet under a new password.
+    pub fn rekey(bytes: &[u8], old_password: &str, new_password: &str) -> Result<Vec<u8>, Error> {
+        crypt::seal(
+            &crypt::open(bytes, old_password)?,
+            new_password,
+            crypt::KdfParams::default(),
+        )
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize)]
 struct ByteVec(#[serde(with = "serde_bytes")] pub Vec<u8>);
 
 #[derive(Deserialize, Serialize)]
-pub struct SerializableWallet {
+pub struct WalletFile {
+    version: u8,
+    #[serde(with = "serde_bytes")]
+    entropy: Vec<u8>,
+    wallets: Vec<PathEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct LegacyWallet {
     #[serde(with = "serde_bytes")]
     entropy: Vec<u8>,
     parent: Option<ByteVec>,
 }
 
+/// Output script for a P2PKH or P2SH address or, on Litecoin and Bitcoin, a
+/// bech32 P2WPKH address.
 pub fn addr_to_script(addr: &str) -> Result<bitcoin::Script, Error> {
     use bitcoin::util::base58;
 
-    let mut addr_bytes = base58::from_check(addr)?;
-    addr_bytes.remove(0);
+    let mut addr_bytes = match base58::from_check(addr) {
+        Ok(b) => b,
+        Err(e) => match bitcoin_bech32::WitnessProgram::from_address(addr) {
+            Ok(program) => return Ok(bitcoin::Script::from(program.to_scriptpubkey())),
+            Err(_) => bail!(Address, "invalid address {}: {}", addr, e),
+        },
+    };
+    if is_p2sh(addr_bytes.remove(0)) {
+        let mut s: Vec<u8> = vec![0xA9, addr_bytes.len() as u8];
+        s.extend(addr_bytes);
+        s.push(0x87);
+        return Ok(bitcoin::Script::from(s));
+    }
 
     let mut s: Vec<u8> = vec![0x76, 0xA9, addr_bytes.len() as u8];
     s.extend(addr_bytes);
@@ -232,34 +364,96 @@ pub fn addr_to_script(addr: &str) -> Result<bitcoin::Script, Error> {
 }
 
 pub fn script_to_addr(script: &bitcoin::Script, network: Network) -> Result<String, Error> {
+    if script.is_v0_p2wpkh() {
+        return pubkey_hash_to_segwit_addr(&script.as_bytes()[2..], network);
+    }
+    if script.is_p2sh() {
+        return Ok(script_hash_to_addr(&script.as_bytes()[2..22], network));
+    }
     if !script.is_p2pkh() {
-        bail!("not p2pkh");
+        bail!(Address, "not p2pkh");
     }
     let mut script = script.iter(true);
     script.next();
     script.next();
     let pkh = match script.next() {
         Some(bitcoin::blockdata::script::Instruction::PushBytes(b)) => b,
-        None => bail!("unexpected end of input"),
-        _ => bail!("invalid opcode"),
+        None => bail!(Address, "unexpected end of input"),
+        _ => bail!(Address, "invalid opcode"),
     };
 
     Ok(pubkey_hash_to_addr(pkh, network))
 }
 
+/// The pubkey hash paid by a P2PKH or P2WPKH script.
+pub fn script_pubkey_hash(script: &bitcoin::Script) -> Option<&[u8]> {
+    if script.is_p2pkh() {
+        Some(&script.as_bytes()[3..23])
+    } else if script.is_v0_p2wpkh() {
+        Some(&script.as_bytes()[2..])
+    } else {
+        None
+    }
+}
+
 pub fn is_p2pkh(first: u8) -> bool {
     first == 0x00 || first == 0x1E || first == 0x30
 }
 
+pub fn is_p2sh(first: u8) -> bool {
+    first == 0x05 || first == 0x16 || first == 0x32
+}
+
+pub fn xpub_version(network: Network) -> [u8; 4] {
+    match network {
+        Network::Dogecoin => [0x02, 0xFA, 0xCA, 0xFD],
+        Network::Litecoin => [0x01, 0x9D, 0xA4, 0x62],
+        Network::Bitcoin => [0x04, 0x88, 0xB2, 0x1E],
+    }
+}
+
+pub fn xprv_version(network: Network) -> [u8; 4] {
+    match network {
+        Network::Dogecoin => [0x02, 0xFA, 0xC3, 0x98],
+        Network::Litecoin => [0x01, 0x9D, 0x9C, 0xFE],
+        Network::Bitcoin => [0x04, 0x88, 0xAD, 0xE4],
+    }
+}
+
+pub fn wif_version(network: Network) -> u8 {
+    match network {
+        Network::Dogecoin => 0x9E,
+        Network::Litecoin => 0xB0,
+        Network::Bitcoin => 0x80,
+    }
+}
+
 pub fn pubkey_hash_to_addr(pkh: &[u8], network: Network) -> String {
     let version_byte: u8 = match network {
         Network::Dogecoin => 0x1E,
         Network::Litecoin => 0x30,
         Network::Bitcoin => 0x00,
     };
+    base58_addr(version_byte, pkh)
+}
+
+pub fn script_hash_to_addr(sh: &[u8], network: Network) -> String {
+    let version_byte: u8 = match network {
+        Network::Dogecoin => 0x16,
+        Network::Litecoin => 0x32,
+        Network::Bitcoin => 0x05,
+    };
+    base58_addr(version_byte, sh)
+}
 
+/// P2SH address of a redeem script.
+pub fn redeem_script_to_addr(script: &[u8], network: Network) -> String {
+    script_hash_to_addr(&hash160(script), network)
+}
+
+fn base58_addr(version_byte: u8, hash: &[u8]) -> String {
     let mut addr_bytes: Vec<u8> = vec![version_byte];
-    addr_bytes.extend(pkh);
+    addr_bytes.extend(hash);
 
     let mut hasher = Sha256::new();
     hasher.input(&addr_bytes);
@@ -273,14 +467,42 @@ pub fn pubkey_hash_to_addr(pkh: &[u8], network: Network) -> String {
     ToBase58::to_base58(addr_bytes.as_slice())
 }
 
-pub fn pubkey_to_addr(pk: &[u8], network: Network) -> String {
+fn bech32_network(network: Network) -> Result<bitcoin_bech32::constants::Network, Error> {
+    Ok(match network {
+        Network::Dogecoin => bail!(Network, "Dogecoin does not support segwit"),
+        Network::Litecoin => bitcoin_bech32::constants::Network::Litecoin,
+        Network::Bitcoin => bitcoin_bech32::constants::Network::Bitcoin,
+    })
+}
+
+/// Bech32 P2WPKH address (`ltc1...` or `bc1...`).
+pub fn pubkey_hash_to_segwit_addr(pkh: &[u8], network: Network) -> Result<String, Error> {
+    use bitcoin_bech32::{u5, WitnessProgram};
+
+    let program = WitnessProgram::new(
+        u5::try_from_u8(0).unwrap(),
+        pkh.to_vec(),
+        bech32_network(network)?,
+    )?;
+    Ok(program.to_address())
+}
+
+pub fn p2wpkh_script(pkh: &[u8]) -> bitcoin::Script {
+    let mut s: Vec<u8> = vec![0x00, pkh.len() as u8];
+    s.extend(pkh);
+    bitcoin::Script::from(s)
+}
+
+pub fn hash160(data: &[u8]) -> Vec<u8> {
     let mut hasher = Sha256::new();
-    hasher.input(pk);
+    hasher.input(data);
     let sha_bytes = hasher.result();
 
     let mut hasher = Ripemd160::new();
     hasher.input(&sha_bytes);
-    let ripe_bytes = hasher.result();
+    hasher.result().to_vec()
+}
 
-    pubkey_hash_to_addr(ripe_bytes.as_slice(), network)
+pub fn pubkey_to_addr(pk: &[u8], network: Network) -> String {
+    pubkey_hash_to_addr(&hash160(pk), network)
 }
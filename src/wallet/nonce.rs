@@ -0,0 +1,146 @@
+use crate::Error;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How long a nonce stays reserved after its bork is completed, so that
+/// indexers can still attach late extensions to the right bork.
+pub fn reassembly_window() -> Duration {
+    Duration::hours(24)
+}
+
+/// How long a nonce stays reserved for a bork that was never marked
+/// complete, after which it is assumed to have been abandoned.
+pub fn reservation_timeout() -> Duration {
+    Duration::days(7)
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OpenNonce {
+    pub nonce: u8,
+    pub allocated: DateTime<Utc>,
+    /// When every part of the bork was broadcast.
+    #[serde(default)]
+    pub completed: Option<DateTime<Utc>>,
+}
+
+/// Hands out bork nonces without reusing one that belongs to a multi-part
+/// bork still being posted, or whose bork was completed within the
+/// reassembly window. Reservations that are never completed or released expire after
+/// the reservation timeout.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct NonceAllocator {
+    next: u8,
+    open: Vec<OpenNonce>,
+}
+impl NonceAllocator {
+    pub fn new(next: u8) -> Self {
+        NonceAllocator {
+            next,
+            open: Vec::new(),
+        }
+    }
+
+    pub fn from_parts(next: u8, open: Vec<OpenNonce>) -> Self {
+        NonceAllocator { next, open }
+    }
+
+    pub fn next(&self) -> u8 {
+        self.next
+    }
+
+    pub fn open(&self) -> &[OpenNonce] {
+        &self.open
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let window = reassembly_window();
+        let timeout = reservation_timeout();
+        self.open.retain(|o| match o.completed {
+            Some(completed) => now.signed_duration_since(completed) < window,
+            None => now.signed_duration_since(o.allocated) < timeout,
+        });
+    }
+
+    pub fn allocate(&mut self, now: DateTime<Utc>) -> Result<u8, Error> {
+        self.prune(now);
+        let open = &self.open;
+        let nonce = (0..=255_u8)
+            .map(|i| self.next.wrapping_add(i))
+            .find(|n| open.iter().all(|o| o.nonce != *n))
+            .ok_or(err!(Encode, "no free nonce: all 256 are in use"))?;
+        self.next = nonce.wrapping_add(1);
+        self.open.push(OpenNonce {
+            nonce,
+            allocated: now,
+            completed: None,
+        });
+        Ok(nonce)
+    }
+
+    /// Marks every part of the bork using `nonce` as broadcast at `now`. The
+    /// nonce stays reserved until the reassembly window has passed since.
+    pub fn complete(&mut self, nonce: u8, now: DateTime<Utc>) {
+        for o in self.open.iter_mut().filter(|o| o.nonce == nonce) {
+            o.completed = Some(now);
+        }
+    }
+
+    /// Returns a nonce whose bork was never broadcast.
+    pub fn release(&mut self, nonce: u8) {
+        self.open.retain(|o| o.nonce != nonce);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_reserved_nonces() {
+        let now = Utc::now();
+        let mut nonces = NonceAllocator::new(254);
+        let first = nonces.allocate(now).unwrap();
+        nonces.complete(first, now);
+        let mut seen = vec![first];
+        for _ in 1..256 {
+            let nonce = nonces.allocate(now).unwrap();
+            assert!(!seen.contains(&nonce));
+            seen.push(nonce);
+        }
+        assert!(nonces.allocate(now).is_err());
+        nonces.release(3);
+        assert_eq!(nonces.allocate(now).unwrap(), 3);
+    }
+
+    #[test]
+    fn reservations_expire() {
+        let now = Utc::now();
+        let mut nonces = NonceAllocator::new(0);
+        let complete = nonces.allocate(now).unwrap();
+        nonces.complete(complete, now);
+        let abandoned = nonces.allocate(now).unwrap();
+
+        nonces.prune(now + reassembly_window());
+        let open: Vec<u8> = nonces.open().iter().map(|o| o.nonce).collect();
+        assert_eq!(open, vec![abandoned]);
+
+        nonces.prune(now + reservation_timeout());
+        assert!(nonces.open().is_empty());
+    }
+
+    #[test]
+    fn reassembly_window_starts_at_completion() {
+        let now = Utc::now();
+        let mut nonces = NonceAllocator::new(0);
+        let slow = nonces.allocate(now).unwrap();
+        let completed = now + Duration::days(3);
+        nonces.complete(slow, completed);
+
+        nonces.prune(completed + reassembly_window() - Duration::seconds(1));
+        assert_eq!(nonces.open().len(), 1);
+        assert_eq!(nonces.open()[0].completed, Some(completed));
+
+        nonces.prune(completed + reassembly_window());
+        assert!(nonces.open().is_empty());
+    }
+}
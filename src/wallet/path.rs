@@ -0,0 +1,191 @@
+use crate::Error;
+use crate::Network;
+use std::fmt;
+use std::str::FromStr;
+
+pub const HARDENED_BIT: u32 = 0x8000_0000;
+
+pub fn coin_type(network: Network) -> u32 {
+    match network {
+        Network::Dogecoin => 3,
+        Network::Litecoin => 2,
+        Network::Bitcoin => 0,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChildNumber {
+    pub index: u32,
+    pub hardened: bool,
+}
+impl ChildNumber {
+    pub fn normal(index: u32) -> Self {
+        ChildNumber {
+            index,
+            hardened: false,
+        }
+    }
+
+    pub fn hardened(index: u32) -> Self {
+        ChildNumber {
+            index,
+            hardened: true,
+        }
+    }
+
+    pub fn from_u32(n: u32) -> Self {
+        ChildNumber {
+            index: n & !HARDENED_BIT,
+            hardened: n & HARDENED_BIT != 0,
+        }
+    }
+
+    pub fn to_u32(&self) -> u32 {
+        if self.hardened {
+            self.index | HARDENED_BIT
+        } else {
+            self.index
+        }
+    }
+}
+impl fmt::Display for ChildNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.hardened {
+            write!(f, "{}'", self.index)
+        } else {
+            write!(f, "{}", self.index)
+        }
+    }
+}
+impl FromStr for ChildNumber {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, hardened) = match s.chars().last() {
+            Some('\'') | Some('h') | Some('H') => (&s[..s.len() - 1], true),
+            _ => (s, false),
+        };
+        let index: u32 = index
+            .parse()
+            .map_err(|_| err!(Decode, "{} is not a valid child number", s))?;
+        ensure!(
+            index < HARDENED_BIT,
+            Decode,
+            "child number {} out of range",
+            s
+        );
+        Ok(ChildNumber { index, hardened })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Chain {
+    External,
+    Change,
+}
+impl Chain {
+    pub fn index(&self) -> u32 {
+        match self {
+            Chain::External => 0,
+            Chain::Change => 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Bip44Path {
+    pub coin_type: u32,
+    pub account: u32,
+    pub chain: Chain,
+    pub index: u32,
+}
+
+/// A BIP32 derivation path such as `m/44'/3'/0'/0/5`, relative to the root
+/// wallet it is applied to.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DerivationPath(Vec<ChildNumber>);
+impl DerivationPath {
+    pub fn new(children: Vec<ChildNumber>) -> Self {
+        DerivationPath(children)
+    }
+
+    pub fn bip44_account(network: Network, account: u32) -> Self {
+        DerivationPath(vec![
+            ChildNumber::hardened(44),
+            ChildNumber::hardened(coin_type(network)),
+            ChildNumber::hardened(account),
+        ])
+    }
+
+    pub fn bip44(network: Network, account: u32, chain: Chain, index: u32) -> Self {
+        Self::bip44_account(network, account)
+            .child(ChildNumber::normal(chain.index()))
+            .child(ChildNumber::normal(index))
+    }
+
+    pub fn child(&self, n: ChildNumber) -> Self {
+        let mut res = self.0.clone();
+        res.push(n);
+        DerivationPath(res)
+    }
+
+    pub fn children(&self) -> &[ChildNumber] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_bip44(&self) -> Option<Bip44Path> {
+        match self.0.as_slice() {
+            [purpose, coin, account, chain, index]
+                if *purpose == ChildNumber::hardened(44)
+                    && coin.hardened
+                    && account.hardened
+                    && !chain.hardened
+                    && !index.hardened =>
+            {
+                Some(Bip44Path {
+                    coin_type: coin.index,
+                    account: account.index,
+                    chain: match chain.index {
+                        0 => Chain::External,
+                        1 => Chain::Change,
+                        _ => return None,
+                    },
+                    index: index.index,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "m")?;
+        for n in &self.0 {
+            write!(f, "/{}", n)?;
+        }
+        Ok(())
+    }
+}
+impl FromStr for DerivationPath {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/').peekable();
+        if parts.peek() == Some(&"m") {
+            parts.next();
+        }
+        Ok(DerivationPath(
+            parts
+                .map(|p| p.parse())
+                .collect::<Result<Vec<ChildNumber>, Error>>()?,
+        ))
+    }
+}
@@ -0,0 +1,294 @@
+use super::path::{ChildNumber, DerivationPath};
+use super::tx::{construct_unsigned, group_inputs, sign_witness_input, PathInputs, Signer};
+use super::ChildWallet;
+use crate::Error;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use std::collections::HashMap;
+
+fn to_bip32(path: &DerivationPath) -> bitcoin::util::bip32::DerivationPath {
+    path.children()
+        .iter()
+        .map(|n| bitcoin::util::bip32::ChildNumber::from(n.to_u32()))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+fn from_bip32(path: &bitcoin::util::bip32::DerivationPath) -> DerivationPath {
+    DerivationPath::new(
+        path.into_iter()
+            .map(|n| ChildNumber::from_u32(u32::from(*n)))
+            .collect(),
+    )
+}
+
+fn to_psbt_key(pubkey: &secp256k1::PublicKey) -> Result<bitcoin::util::key::PublicKey, Error> {
+    Ok(bitcoin::util::key::PublicKey::from_slice(
+        &pubkey.serialize_compressed(),
+    )?)
+}
+
+/// The output spent by `txin`, as recorded in its PSBT input.
+fn spent_output<'a>(
+    input: &'a bitcoin::util::psbt::Input,
+    txin: &bitcoin::TxIn,
+) -> Option<&'a bitcoin::TxOut> {
+    let prevout = &txin.previous_output;
+    match (&input.witness_utxo, &input.non_witness_utxo) {
+        (Some(utxo), _) => Some(utxo),
+        (None, Some(prev)) if prev.txid() == prevout.txid => prev.output.get(prevout.vout as usize),
+        _ => None,
+    }
+}
+
+/// Whether `input` already has its final script_sig or witness.
+fn is_finalized(input: &bitcoin::util::psbt::Input) -> bool {
+    input.final_script_sig.is_some() || input.final_script_witness.is_some()
+}
+
+fn decode_psbt(psbt: &[u8]) -> Result<PartiallySignedTransaction, Error> {
+    Ok(bitcoin::consensus::encode::deserialize(psbt)?)
+}
+
+fn encode_psbt(psbt: &PartiallySignedTransaction) -> Vec<u8> {
+    bitcoin::consensus::encode::serialize(psbt)
+}
+
+impl ChildWallet {
+    /// Builds an unsigned PSBT like `Wallet::construct_signed`, with paths
+    /// relative to this wallet, which may be watch-only. Each input records
+    /// its previous transaction and its key's origin as this wallet's
+    /// fingerprint and path, so the same wallet can sign it elsewhere.
+    pub fn construct_psbt(
+        &mut self,
+        author: &DerivationPath,
+        inputs: &[PathInputs],
+        outputs: &[(&str, u64)],
+        fee: u64,
+        op_return: Option<&[u8]>,
+    ) -> Result<Vec<u8>, Error> {
+        use bitcoin::consensus::encode::deserialize;
+        use bitcoin::{SigHashType, Transaction};
+
+        let fingerprint = bitcoin::util::bip32::Fingerprint::from(&self.fingerprint()[..]);
+        let groups = group_inputs(author, inputs);
+        let mut owners = Vec::new();
+        let mut prev_txs = HashMap::new();
+        for (path, txs) in &groups {
+            let child = self.derive(path)?;
+            owners.push((child.scripts(), txs.as_slice()));
+            for raw in txs {
+                let tx: Transaction = deserialize(raw)?;
+                prev_txs.insert(tx.txid(), tx);
+            }
+        }
+
+        let (tx, owned_by, prevouts) = construct_unsigned(&owners, outputs, fee, op_return)?;
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx.clone())?;
+        for (i, (input, owner)) in psbt.inputs.iter_mut().zip(owned_by).enumerate() {
+            let path = groups[owner].0;
+            let pubkey = to_psbt_key(self.derive(path)?.mpub())?;
+            input.non_witness_utxo = prev_txs.get(&tx.input[i].previous_output.txid).cloned();
+            if prevouts[i].script_pubkey.is_v0_p2wpkh() {
+                input.witness_utxo = Some(prevouts[i].clone());
+            }
+            input.sighash_type = Some(SigHashType::All);
+            input
+                .hd_keypaths
+                .insert(pubkey, (fingerprint, to_bip32(path)));
+        }
+        Ok(encode_psbt(&psbt))
+    }
+
+    /// Adds signatures to every input of `psbt` whose key origin is this
+    /// wallet. Fails if there are none, or if any of them requests a
+    /// sighash type other than `ALL`.
+    pub fn sign_psbt(&mut self, psbt: &[u8]) -> Result<Vec<u8>, Error> {
+        use bitcoin::SigHashType;
+        use bitcoin_hashes::Hash;
+
+        let fingerprint = bitcoin::util::bip32::Fingerprint::from(&self.fingerprint()[..]);
+        let mut psbt = decode_psbt(psbt)?;
+        let tx = psbt.global.unsigned_tx.clone();
+        let mut signed = 0;
+        for (i, input) in psbt.inputs.iter_mut().enumerate() {
+            let origins: Vec<_> = input
+                .hd_keypaths
+                .iter()
+                .filter(|(_, (fp, _))| *fp == fingerprint)
+                .map(|(pubkey, (_, path))| (*pubkey, from_bip32(path)))
+                .collect();
+            for (pubkey, path) in origins {
+                let child = self.derive(&path)?;
+                ensure!(
+                    to_psbt_key(child.mpub())? == pubkey,
+                    Wallet,
+                    "key at {} does not match PSBT input {}",
+                    path,
+                    i
+                );
+                let spent = match spent_output(input, &tx.input[i]) {
+                    Some(spent) => spent.clone(),
+                    None => bail!(Decode, "PSBT input {} is missing the output it spends", i),
+                };
+
+                ensure!(
+                    input.sighash_type.unwrap_or(SigHashType::All) == SigHashType::All,
+                    Wallet,
+                    "PSBT input {} requests an unsupported sighash type",
+                    i
+                );
+
+                let sig = if Some(&spent.script_pubkey) == child.witness_script().as_ref() {
+                    sign_witness_input(&tx, i, child, spent.value)?.remove(0)
+                } else {
+                    ensure!(
+                        spent.script_pubkey == child.script(),
+                        Wallet,
+                        "PSBT input {} does not spend an output held by {}",
+                        i,
+                        path
+                    );
+                    let sighash_type = SigHashType::All.as_u32();
+                    let sighash = tx
+                        .signature_hash(i, &child.script(), sighash_type)
+                        .into_inner();
+                    let sig =
+                        secp256k1::sign(&secp256k1::Message::parse(&sighash), child.secret_key()?)?
+                            .0;
+                    let mut sig = sig.serialize_der().as_ref().to_vec();
+                    sig.push(sighash_type as u8);
+                    sig
+                };
+                input.partial_sigs.insert(pubkey, sig);
+                signed += 1;
+            }
+        }
+        ensure!(signed > 0, Wallet, "no PSBT inputs belong to this wallet");
+        Ok(encode_psbt(&psbt))
+    }
+}
+
+/// Turns the signature on each P2PKH or P2WPKH input of `psbt` into its
+/// final script_sig or witness. P2WPKH inputs get no final script_sig, as
+/// BIP174 requires for native segwit spends.
+pub fn finalize_psbt(psbt: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut psbt = decode_psbt(psbt)?;
+    let tx = psbt.global.unsigned_tx.clone();
+    for (i, input) in psbt.inputs.iter_mut().enumerate() {
+        if is_finalized(input) {
+            continue;
+        }
+        ensure!(
+            input.partial_sigs.len() == 1,
+            Encode,
+            "PSBT input {} has {} signatures, expected 1",
+            i,
+            input.partial_sigs.len()
+        );
+        let witness =
+            spent_output(input, &tx.input[i]).is_some_and(|o| o.script_pubkey.is_v0_p2wpkh());
+        let (pubkey, sig) = input.partial_sigs.drain().next().unwrap();
+        let mut pubkey_bytes = Vec::new();
+        pubkey.write_into(&mut pubkey_bytes);
+        if witness {
+            input.final_script_witness = Some(vec![sig, pubkey_bytes]);
+        } else {
+            input.final_script_sig = Some(
+                bitcoin::blockdata::script::Builder::new()
+                    .push_slice(&sig)
+                    .push_slice(&pubkey_bytes)
+                    .into_script(),
+            );
+        }
+        input.sighash_type = None;
+        input.hd_keypaths.clear();
+    }
+    Ok(encode_psbt(&psbt))
+}
+
+/// Extracts the signed transaction from a finalized PSBT.
+pub fn extract_psbt(psbt: &[u8]) -> Result<Vec<u8>, Error> {
+    let psbt = decode_psbt(psbt)?;
+    for (i, input) in psbt.inputs.iter().enumerate() {
+        ensure!(
+            is_finalized(input),
+            Encode,
+            "PSBT input {} is not finalized",
+            i
+        );
+    }
+    Ok(bitcoin::consensus::encode::serialize(&psbt.extract_tx()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::encode::{deserialize, serialize};
+    use bitcoin::{SigHashType, Transaction, TxOut};
+
+    /// A PSBT spending one P2WPKH and one P2PKH output held by m/0 of
+    /// `wallet`.
+    fn unsigned(wallet: &mut ChildWallet) -> Vec<u8> {
+        let path: DerivationPath = "m/0".parse().unwrap();
+        let child = wallet.derive(&path).unwrap();
+        let funding = serialize(&Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: child.witness_script().unwrap(),
+                },
+                TxOut {
+                    value: 50_000,
+                    script_pubkey: child.script(),
+                },
+            ],
+        });
+        let inputs = [PathInputs {
+            path: path.clone(),
+            txs: vec![funding],
+        }];
+        wallet
+            .construct_psbt(&path, &inputs, &[], 1000, None)
+            .unwrap()
+    }
+
+    #[test]
+    fn refuses_non_all_sighash_on_legacy_inputs() {
+        let mut wallet = ChildWallet::new([7; 64]).unwrap();
+        let mut psbt = decode_psbt(&unsigned(&mut wallet)).unwrap();
+        for input in &mut psbt.inputs {
+            if input.witness_utxo.is_none() {
+                input.sighash_type = Some(SigHashType::SinglePlusAnyoneCanPay);
+            }
+        }
+        match wallet.sign_psbt(&encode_psbt(&psbt)) {
+            Err(Error::Wallet(_)) => (),
+            other => panic!("expected a wallet error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn finalized_witness_input_has_no_script_sig() {
+        let mut wallet = ChildWallet::new([7; 64]).unwrap();
+        let unsigned = unsigned(&mut wallet);
+        let signed = wallet.sign_psbt(&unsigned).unwrap();
+        let finalized = finalize_psbt(&signed).unwrap();
+        let psbt = decode_psbt(&finalized).unwrap();
+        let tx: Transaction = deserialize(&extract_psbt(&finalized).unwrap()).unwrap();
+        let witnesses: Vec<_> = psbt
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.is_some())
+            .collect();
+        assert_eq!(witnesses.iter().filter(|w| **w).count(), 1);
+        for ((input, txin), witness) in psbt.inputs.iter().zip(&tx.input).zip(witnesses) {
+            assert_eq!(input.final_script_sig.is_none(), witness);
+            assert_eq!(input.final_script_witness.is_some(), witness);
+            assert_eq!(txin.script_sig.is_empty(), witness);
+            assert_eq!(txin.witness.is_empty(), !witness);
+        }
+    }
+}
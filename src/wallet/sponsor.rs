@@ -0,0 +1,330 @@
+use super::addr_to_script;
+use super::tx::{find_utxos, op_return_output, sign_input, sign_spend, Signer};
+use crate::protocol::{diagnose_tx, tx_author};
+use crate::Error;
+use crate::Network;
+
+/// `SIGHASH_SINGLE | SIGHASH_ANYONECANPAY`: the author's signature covers
+/// only input 0 and output 0, so a sponsor can add inputs and outputs.
+pub const SPONSORED_SIGHASH: u32 = 0x83;
+
+/// Builds the author's half of a sponsored bork: one output held by `author`
+/// spent as input 0, and `op_return` as output 0, signed so that only those
+/// two are committed to. The value of the author's input goes to the
+/// sponsor as change, so it is usually dust the sponsor sent beforehand. It
+/// must be a P2PKH output, since only P2PKH inputs are signed here with this
+/// sighash type.
+///
+/// `outputs` (recipients and mentions) follow the OP_RETURN but are not
+/// covered by the author's signature.
+pub fn construct_sponsor_request<S: Signer>(
+    author: &S,
+    inputs: &[Vec<u8>],
+    outputs: &[(&str, u64)],
+    op_return: &[u8],
+) -> Result<Vec<u8>, Error> {
+    use bitcoin::consensus::Encodable;
+    use bitcoin::{Transaction, TxIn, TxOut};
+
+    author.secret_key()?;
+    let utxo = match find_utxos(inputs, &[author.script()])?.into_iter().next() {
+        Some(u) => u,
+        None => bail!(Funds, "author has no funds among inputs"),
+    };
+
+    let output = std::iter::once(Ok(op_return_output(op_return)))
+        .chain(outputs.iter().map(|(addr, val)| -> Result<_, Error> {
+            Ok(TxOut {
+                script_pubkey: addr_to_script(addr)?,
+                value: *val,
+            })
+        }))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let mut tx = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: utxo.0,
+            script_sig: bitcoin::Script::new(),
+            sequence: 0xFFFFFFFF_u32,
+            witness: vec![],
+        }],
+        output,
+    };
+    tx.input[0].script_sig = sign_input(&tx, 0, author, SPONSORED_SIGHASH)?;
+
+    let mut res = Vec::new();
+    tx.consensus_encode(&mut res)?;
+    Ok(res)
+}
+
+/// Completes a request from `construct_sponsor_request`, paying for its
+/// outputs and `fee` from outputs held by `sponsor`, who receives the
+/// change, including the value of the author's input. That input's amount
+/// is not part of the request, so the transaction it spends must be among
+/// `author_inputs`. Fails if indexers would reject the result or not
+/// attribute it to the author, such as a comment with no recipient output.
+pub fn complete_sponsored<S: Signer>(
+    request: &[u8],
+    author_inputs: &[Vec<u8>],
+    sponsor: &S,
+    inputs: &[Vec<u8>],
+    fee: u64,
+) -> Result<Vec<u8>, Error> {
+    use bitcoin::consensus::{Decodable, Encodable};
+    use bitcoin::{Transaction, TxIn, TxOut};
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    let mut tx = Transaction::consensus_decode(&mut Cursor::new(request))?;
+    ensure!(
+        tx.input.len() == 1,
+        Decode,
+        "request must have exactly one input"
+    );
+    ensure!(
+        tx.output
+            .first()
+            .is_some_and(|o| o.script_pubkey.is_op_return()),
+        Decode,
+        "request output 0 must be an OP_RETURN"
+    );
+    ensure!(
+        tx.output
+            .iter()
+            .filter(|o| o.script_pubkey.is_op_return())
+            .count()
+            == 1,
+        Decode,
+        "request must have exactly one OP_RETURN output"
+    );
+
+    let author_outpoint = tx.input[0].previous_output;
+    let author_prevout = match author_inputs
+        .iter()
+        .map(|i| Transaction::consensus_decode(&mut Cursor::new(i)))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|t| t.txid() == author_outpoint.txid)
+        .and_then(|t| t.output.into_iter().nth(author_outpoint.vout as usize))
+    {
+        Some(o) => o,
+        None => bail!(Funds, "author input is not among author_inputs"),
+    };
+    let author_value = author_prevout.value;
+    let mut known = HashMap::new();
+    known.insert(author_outpoint, author_prevout);
+    let author = match tx_author(&tx, Network::Bitcoin, &mut known) {
+        Some(author) => author,
+        None => bail!(Decode, "request is not signed by its author"),
+    };
+
+    sponsor.secret_key()?;
+    let script = sponsor.script();
+    let (outpoints, prevouts): (Vec<_>, Vec<_>) = find_utxos(inputs, &sponsor.scripts())?
+        .into_iter()
+        .filter(|u| u.0 != author_outpoint)
+        .unzip();
+    let input_size = prevouts.iter().fold(author_value, |acc, o| acc + o.value);
+    let output_size = tx.output.iter().fold(0, |acc, o| acc + o.value);
+    if input_size < fee || output_size > input_size - fee {
+        bail!(Funds, "insufficient funds")
+    }
+    let change = input_size - output_size - fee;
+
+    tx.input
+        .extend(outpoints.into_iter().map(|previous_output| TxIn {
+            previous_output,
+            script_sig: bitcoin::Script::new(),
+            sequence: 0xFFFFFFFF_u32,
+            witness: vec![],
+        }));
+    if change > 0 {
+        tx.output.push(TxOut {
+            script_pubkey: script,
+            value: change,
+        });
+    }
+    for (i, prevout) in prevouts.iter().enumerate() {
+        sign_spend(&mut tx, i + 1, sponsor, prevout)?;
+    }
+    known.extend(
+        tx.input[1..]
+            .iter()
+            .map(|i| i.previous_output)
+            .zip(prevouts),
+    );
+    ensure!(
+        tx_author(&tx, Network::Bitcoin, &mut known).as_ref() == Some(&author),
+        Encode,
+        "author signature does not allow sponsoring"
+    );
+    let now = chrono::Utc::now();
+    if let Some(Err(rejection)) = diagnose_tx(&tx, now, Network::Bitcoin, &mut known) {
+        bail!(Encode, "indexers would reject it: {}", rejection.reason);
+    }
+
+    let mut res = Vec::new();
+    tx.consensus_encode(&mut res)?;
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{self, BorkMessage, NoPrevouts, RejectReason};
+    use crate::KeyWallet;
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin::{Transaction, TxOut};
+
+    fn wallet(byte: u8) -> KeyWallet {
+        KeyWallet::new(secp256k1::SecretKey::parse(&[byte; 32]).unwrap(), true)
+    }
+
+    fn funding(script: bitcoin::Script, value: u64) -> Vec<u8> {
+        serialize(&Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script,
+            }],
+        })
+    }
+
+    fn bork(content: &str) -> Vec<u8> {
+        let message = BorkMessage::Bork {
+            nonce: 0,
+            content: content.to_owned(),
+        };
+        protocol::encode(&message).unwrap().remove(0)
+    }
+
+    /// A request spending 5,000 from `author`, and the transaction paying it.
+    fn request(author: &KeyWallet) -> (Transaction, Vec<u8>) {
+        request_for(author, &[], &bork("mine"))
+    }
+
+    fn request_for(
+        author: &KeyWallet,
+        outputs: &[(&str, u64)],
+        op_return: &[u8],
+    ) -> (Transaction, Vec<u8>) {
+        let inputs = [funding(author.script(), 5000)];
+        let request = construct_sponsor_request(author, &inputs, outputs, op_return).unwrap();
+        (deserialize(&request).unwrap(), inputs[0].clone())
+    }
+
+    fn comment() -> Vec<u8> {
+        let message = BorkMessage::Comment {
+            nonce: 0,
+            reference_id: vec![0xab; 2],
+            content: "yes".to_owned(),
+        };
+        protocol::encode(&message).unwrap().remove(0)
+    }
+
+    /// Completes `request` with 100,000 from `sponsor` and a fee of 1,000.
+    fn complete(
+        (request, author_input): &(Transaction, Vec<u8>),
+        sponsor: &KeyWallet,
+    ) -> Result<Transaction, Error> {
+        let inputs = [funding(sponsor.script(), 100_000)];
+        let author_inputs = [author_input.clone()];
+        let tx = complete_sponsored(&serialize(request), &author_inputs, sponsor, &inputs, 1000)?;
+        Ok(deserialize(&tx).unwrap())
+    }
+
+    #[test]
+    fn sponsored_bork_is_credited_to_author() {
+        let (author, sponsor) = (wallet(1), wallet(2));
+        let tx = complete(&request(&author), &sponsor).unwrap();
+        let bork =
+            protocol::diagnose_tx(&tx, chrono::Utc::now(), Network::Bitcoin, &mut NoPrevouts)
+                .unwrap()
+                .unwrap();
+        assert_eq!(bork.sender_address, author.address(Network::Bitcoin));
+        assert!(bork.mentions.is_empty());
+    }
+
+    #[test]
+    fn sponsor_change_is_not_the_recipient() {
+        let (author, sponsor, friend) = (wallet(1), wallet(2), wallet(3));
+        let to = friend.address(Network::Bitcoin);
+        let request = request_for(&author, &[(&to, 1000)], &comment());
+        let tx = complete(&request, &sponsor).unwrap();
+        let bork =
+            protocol::diagnose_tx(&tx, chrono::Utc::now(), Network::Bitcoin, &mut NoPrevouts)
+                .unwrap()
+                .unwrap();
+        assert_eq!(bork.recipient_address, Some(to));
+        assert!(bork.mentions.is_empty());
+    }
+
+    #[test]
+    fn request_missing_its_recipient_is_refused() {
+        let (author, sponsor) = (wallet(1), wallet(2));
+        match complete(&request_for(&author, &[], &comment()), &sponsor) {
+            Err(Error::Encode(_)) => (),
+            other => panic!("expected an encode error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn author_input_value_goes_to_the_sponsor() {
+        let (author, sponsor) = (wallet(1), wallet(2));
+        let tx = complete(&request(&author), &sponsor).unwrap();
+        let outputs = tx.output.iter().fold(0, |acc, o| acc + o.value);
+        assert_eq!(outputs, 5000 + 100_000 - 1000);
+        let change = tx.output.last().unwrap();
+        assert_eq!(change.script_pubkey, sponsor.script());
+        assert_eq!(change.value, outputs);
+    }
+
+    #[test]
+    fn author_input_must_be_known() {
+        let (author, sponsor) = (wallet(1), wallet(2));
+        let (request, _) = request(&author);
+        let other = funding(author.script(), 5000 + 1);
+        match complete(&(request, other), &sponsor) {
+            Err(Error::Funds(_)) => (),
+            other => panic!("expected a funds error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn request_with_extra_op_return_is_refused() {
+        let (author, sponsor) = (wallet(1), wallet(2));
+        let mut request = request(&author);
+        request.0.output.push(op_return_output(&bork("forged")));
+        match complete(&request, &sponsor) {
+            Err(Error::Decode(_)) => (),
+            other => panic!("expected a decode error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn injected_op_return_is_not_credited() {
+        let (author, sponsor) = (wallet(1), wallet(2));
+        let mut tx = complete(&request(&author), &sponsor).unwrap();
+        tx.output.push(op_return_output(&bork("forged")));
+        assert!(tx_author(&tx, Network::Bitcoin, &mut NoPrevouts).is_some());
+
+        let (parsed, _, _) = protocol::parse_tx(
+            tx.clone(),
+            chrono::Utc::now(),
+            Network::Bitcoin,
+            &mut NoPrevouts,
+        );
+        assert!(parsed.is_none());
+        let rejection =
+            protocol::diagnose_tx(&tx, chrono::Utc::now(), Network::Bitcoin, &mut NoPrevouts)
+                .unwrap()
+                .unwrap_err();
+        match rejection.reason {
+            RejectReason::MultipleOpReturns(2) => (),
+            reason => panic!("unexpected rejection {}", reason),
+        }
+    }
+}
@@ -0,0 +1,726 @@
+use super::key::KeyWallet;
+use super::path::DerivationPath;
+use super::{addr_to_script, hash160, p2wpkh_script, script_to_addr, Wallet};
+use crate::protocol::{diagnose_tx, tx_author};
+use crate::Error;
+use crate::Network;
+use secp256k1::SecretKey;
+use std::collections::HashMap;
+
+/// A single key able to sign P2PKH and P2WPKH inputs.
+pub trait Signer {
+    fn secret_key(&self) -> Result<&SecretKey, Error>;
+
+    /// Public key as it appears in the script_sig, compressed or not.
+    fn public_key(&self) -> Vec<u8>;
+
+    /// P2PKH script, which also receives change.
+    fn script(&self) -> bitcoin::Script;
+
+    /// P2WPKH script for the same key. Uncompressed keys have none.
+    fn witness_script(&self) -> Option<bitcoin::Script> {
+        let pubkey = self.public_key();
+        if pubkey.len() == 33 {
+            Some(p2wpkh_script(&hash160(&pubkey)))
+        } else {
+            None
+        }
+    }
+
+    /// Every output script this key can spend.
+    fn scripts(&self) -> Vec<bitcoin::Script> {
+        std::iter::once(self.script())
+            .chain(self.witness_script())
+            .collect()
+    }
+}
+
+pub fn sign_input<S: Signer + ?Sized>(
+    tx: &bitcoin::Transaction,
+    i: usize,
+    signer: &S,
+    sighash_type: u32,
+) -> Result<bitcoin::Script, Error> {
+    use bitcoin_hashes::Hash;
+
+    let sighash = tx
+        .signature_hash(i, &signer.script(), sighash_type)
+        .into_inner();
+    let sig = secp256k1::sign(&secp256k1::Message::parse(&sighash), signer.secret_key()?)?.0;
+    let pubkey = signer.public_key();
+    let sig_der = sig.serialize_der();
+    Ok(bitcoin::Script::from(
+        [
+            &[sig_der.as_ref().len() as u8 + 1][..],
+            sig_der.as_ref(),
+            &[sighash_type as u8, pubkey.len() as u8][..],
+            &pubkey[..],
+        ]
+        .concat(),
+    ))
+}
+
+/// BIP143 signature for P2WPKH input `i` of `tx`, spending `value`, as a
+/// witness stack.
+pub fn sign_witness_input<S: Signer + ?Sized>(
+    tx: &bitcoin::Transaction,
+    i: usize,
+    signer: &S,
+    value: u64,
+) -> Result<Vec<Vec<u8>>, Error> {
+    use bitcoin::util::bip143::SighashComponents;
+    use bitcoin_hashes::Hash;
+
+    let sighash = SighashComponents::new(tx)
+        .sighash_all(&tx.input[i], &signer.script(), value)
+        .into_inner();
+    let sig = secp256k1::sign(&secp256k1::Message::parse(&sighash), signer.secret_key()?)?.0;
+    let mut sig = sig.serialize_der().as_ref().to_vec();
+    sig.push(0x01);
+    Ok(vec![sig, signer.public_key()])
+}
+
+/// Signs input `i` of `tx` with SIGHASH_ALL, as P2PKH or P2WPKH depending
+/// on `prevout`, the output it spends.
+pub fn sign_spend<S: Signer + ?Sized>(
+    tx: &mut bitcoin::Transaction,
+    i: usize,
+    signer: &S,
+    prevout: &bitcoin::TxOut,
+) -> Result<(), Error> {
+    if prevout.script_pubkey == signer.script() {
+        tx.input[i].script_sig = sign_input(tx, i, signer, 0x01)?;
+    } else if Some(&prevout.script_pubkey) == signer.witness_script().as_ref() {
+        tx.input[i].witness = sign_witness_input(tx, i, signer, prevout.value)?;
+    } else {
+        bail!(Wallet, "input {} is not held by its signer", i)
+    }
+    Ok(())
+}
+
+pub fn op_return_output(data: &[u8]) -> bitcoin::TxOut {
+    let mut s: Vec<u8> = vec![0x6a, 0x4c, data.len() as u8];
+    s.extend(data.iter());
+    bitcoin::TxOut {
+        script_pubkey: bitcoin::Script::from(s),
+        value: 0,
+    }
+}
+
+/// Outputs of the given raw transactions that pay to any of `scripts`.
+pub fn find_utxos(
+    inputs: &[Vec<u8>],
+    scripts: &[bitcoin::Script],
+) -> Result<Vec<(bitcoin::OutPoint, bitcoin::TxOut)>, Error> {
+    use bitcoin::consensus::Decodable;
+    use bitcoin::{OutPoint, Transaction};
+    use std::io::Cursor;
+
+    Ok(inputs
+        .iter()
+        .map(|i| Transaction::consensus_decode(&mut Cursor::new(i)))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flat_map(|tx| {
+            let txid = tx.txid();
+            tx.output
+                .into_iter()
+                .enumerate()
+                .filter(|(_, o)| scripts.contains(&o.script_pubkey))
+                .map(move |(vout, o)| {
+                    (
+                        OutPoint {
+                            txid,
+                            vout: vout as u32,
+                        },
+                        o,
+                    )
+                })
+        })
+        .collect())
+}
+
+fn unsigned_input(previous_output: bitcoin::OutPoint) -> bitcoin::TxIn {
+    bitcoin::TxIn {
+        previous_output,
+        script_sig: bitcoin::Script::new(),
+        sequence: 0xFFFFFFFF_u32,
+        witness: vec![],
+    }
+}
+
+/// Signs input `i` of `tx`, spending `prevouts[i]`, with `signers[i]`. If
+/// `author` is given, checks that indexers will accept the bork and
+/// attribute it to `author`.
+fn sign_and_encode(
+    mut tx: bitcoin::Transaction,
+    signers: &[&dyn Signer],
+    prevouts: &[bitcoin::TxOut],
+    author: Option<&dyn Signer>,
+) -> Result<Vec<u8>, Error> {
+    use bitcoin::consensus::Encodable;
+
+    for i in 0..tx.input.len() {
+        sign_spend(&mut tx, i, signers[i], &prevouts[i])?;
+    }
+    if let Some(author) = author {
+        check_author(&tx, author, prevouts)?;
+        let mut prevouts = spent_outputs(&tx, prevouts);
+        let now = chrono::Utc::now();
+        if let Some(Err(rejection)) = diagnose_tx(&tx, now, Network::Bitcoin, &mut prevouts) {
+            bail!(Encode, "indexers would reject it: {}", rejection.reason);
+        }
+    }
+    let mut res = Vec::new();
+    tx.consensus_encode(&mut res)?;
+    Ok(res)
+}
+
+/// The outputs spent by the inputs of `tx`, `prevouts[i]` by input `i`.
+fn spent_outputs(
+    tx: &bitcoin::Transaction,
+    prevouts: &[bitcoin::TxOut],
+) -> HashMap<bitcoin::OutPoint, bitcoin::TxOut> {
+    tx.input
+        .iter()
+        .map(|i| i.previous_output)
+        .zip(prevouts.iter().cloned())
+        .collect()
+}
+
+/// Fails unless input 0 of `tx` carries a valid signature by `author`.
+/// `prevouts[i]` is the output spent by input `i`, needed to check a
+/// segwit signature.
+pub fn check_author<S: Signer + ?Sized>(
+    tx: &bitcoin::Transaction,
+    author: &S,
+    prevouts: &[bitcoin::TxOut],
+) -> Result<(), Error> {
+    let expected = script_to_addr(&author.script(), Network::Bitcoin)?;
+    let mut prevouts = spent_outputs(tx, prevouts);
+    ensure!(
+        tx_author(tx, Network::Bitcoin, &mut prevouts).as_ref() == Some(&expected),
+        Wallet,
+        "input 0 is not signed by the author {}",
+        expected
+    );
+    Ok(())
+}
+
+/// Rough virtual size of a signed transaction spending `prevouts`, each
+/// P2PKH or P2WPKH, into P2PKH outputs.
+pub fn estimate_vsize(prevouts: &[bitcoin::TxOut], outputs: usize, compressed: bool) -> u64 {
+    let inputs = prevouts.iter().fold(0, |acc, o| {
+        acc + if o.script_pubkey.is_v0_p2wpkh() {
+            68
+        } else if compressed {
+            148
+        } else {
+            180
+        }
+    });
+    10 + inputs + 34 * outputs as u64
+}
+
+pub fn construct_signed<S: Signer>(
+    signer: &S,
+    inputs: &[Vec<u8>],
+    outputs: &[(&str, u64)],
+    fee: u64,
+    op_return: Option<&[u8]>,
+) -> Result<Vec<u8>, Error> {
+    construct_signed_multi(&[(signer, inputs)], outputs, fee, op_return)
+}
+
+/// Like `construct_signed`, but spends outputs paying any of several keys,
+/// each from its own list of raw transactions. The first signer is the
+/// author: its inputs come first so the bork is attributed to it, and it
+/// receives the change. When posting a bork, the author must have funds
+/// among its inputs.
+pub fn construct_signed_multi(
+    signers: &[(&dyn Signer, &[Vec<u8>])],
+    outputs: &[(&str, u64)],
+    fee: u64,
+    op_return: Option<&[u8]>,
+) -> Result<Vec<u8>, Error> {
+    let author = match signers.first() {
+        Some((author, _)) => *author,
+        None => bail!(Wallet, "no signers"),
+    };
+    let owners = signers
+        .iter()
+        .map(|(signer, txs)| -> Result<_, Error> {
+            signer.secret_key()?;
+            Ok((signer.scripts(), *txs))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let (tx, owned_by, prevouts) = construct_unsigned(&owners, outputs, fee, op_return)?;
+    let signers: Vec<_> = owned_by.into_iter().map(|i| signers[i].0).collect();
+    sign_and_encode(tx, &signers, &prevouts, op_return.map(|_| author))
+}
+
+/// The unsigned transaction behind `construct_signed_multi`, with each
+/// owner given by the scripts it can spend, the first receiving change.
+/// Also returns the index of the owner of each input and the output it
+/// spends.
+pub fn construct_unsigned(
+    owners: &[(Vec<bitcoin::Script>, &[Vec<u8>])],
+    outputs: &[(&str, u64)],
+    fee: u64,
+    op_return: Option<&[u8]>,
+) -> Result<(bitcoin::Transaction, Vec<usize>, Vec<bitcoin::TxOut>), Error> {
+    use bitcoin::{Transaction, TxOut};
+
+    let script = match owners.first().and_then(|(scripts, _)| scripts.first()) {
+        Some(script) => script.clone(),
+        None => bail!(Wallet, "no signers"),
+    };
+
+    let mut inputs = Vec::new();
+    for (idx, (owner, txs)) in owners.iter().enumerate() {
+        inputs.extend(find_utxos(txs, owner)?.into_iter().map(|u| (u, idx)));
+    }
+    if op_return.is_some() {
+        // Indexers credit a bork to the signer of input 0. A P2PKH input is
+        // preferred, since crediting a P2WPKH one needs the output it spends.
+        let author_inputs = inputs.iter().enumerate().filter(|(_, i)| i.1 == 0);
+        let pos = match author_inputs
+            .clone()
+            .find(|(_, i)| (i.0).1.script_pubkey == script)
+            .or_else(|| author_inputs.clone().next())
+        {
+            Some((pos, _)) => pos,
+            None => bail!(Funds, "author has no funds among inputs"),
+        };
+        let author_input = inputs.remove(pos);
+        inputs.insert(0, author_input);
+    }
+    let input_size = inputs.iter().fold(0, |acc, i| acc + (i.0).1.value);
+    let output_size = outputs.iter().fold(0, |acc, o| acc + o.1);
+    if input_size < fee || output_size > input_size - fee {
+        bail!(Funds, "insufficient funds")
+    }
+
+    let output = outputs
+        .iter()
+        .map(|(addr, val)| -> Result<_, Error> {
+            Ok(TxOut {
+                script_pubkey: addr_to_script(addr)?,
+                value: *val,
+            })
+        })
+        .chain(std::iter::once(Ok(TxOut {
+            script_pubkey: script,
+            value: input_size - output_size - fee,
+        })))
+        .chain(op_return.into_iter().map(|data| Ok(op_return_output(data))))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let input = inputs.iter().map(|i| unsigned_input((i.0).0)).collect();
+    let tx = Transaction {
+        version: 1,
+        lock_time: 0,
+        input,
+        output,
+    };
+    let (utxos, owned_by): (Vec<_>, Vec<_>) = inputs.into_iter().unzip();
+    Ok((tx, owned_by, utxos.into_iter().map(|u| u.1).collect()))
+}
+
+/// Builds a bork authored by a key that does not pay for it. A single output
+/// held by `author`, P2PKH if there is one, is spent as input 0 and its full
+/// value returned to the author. The funders pay for `outputs` and the fee, and the first of them
+/// receives the change, which indexers do not count as a recipient or
+/// mention. Fails if indexers would reject the bork, such as a comment with
+/// no recipient in `outputs`.
+pub fn construct_authored(
+    author: &dyn Signer,
+    author_inputs: &[Vec<u8>],
+    funders: &[(&dyn Signer, &[Vec<u8>])],
+    outputs: &[(&str, u64)],
+    fee: u64,
+    op_return: &[u8],
+) -> Result<Vec<u8>, Error> {
+    use bitcoin::{Transaction, TxOut};
+
+    author.secret_key()?;
+    let author_utxos = find_utxos(author_inputs, &author.scripts())?;
+    let author_utxo = match author_utxos
+        .iter()
+        .find(|u| u.1.script_pubkey == author.script())
+        .or_else(|| author_utxos.first())
+    {
+        Some(u) => u.clone(),
+        None => bail!(Funds, "author has no funds among inputs"),
+    };
+    let change_script = match funders.first() {
+        Some((funder, _)) => funder.script(),
+        None => bail!(Funds, "no funders"),
+    };
+
+    let mut inputs = vec![(author_utxo.clone(), author)];
+    for (signer, txs) in funders {
+        signer.secret_key()?;
+        inputs.extend(
+            find_utxos(txs, &signer.scripts())?
+                .into_iter()
+                .filter(|u| u.0 != author_utxo.0)
+                .map(|u| (u, *signer)),
+        );
+    }
+    let funding = inputs[1..].iter().fold(0, |acc, i| acc + (i.0).1.value);
+    let output_size = outputs.iter().fold(0, |acc, o| acc + o.1);
+    if funding < fee || output_size > funding - fee {
+        bail!(Funds, "insufficient funds")
+    }
+    let change = funding - output_size - fee;
+
+    let mut output = outputs
+        .iter()
+        .map(|(addr, val)| -> Result<_, Error> {
+            Ok(TxOut {
+                script_pubkey: addr_to_script(addr)?,
+                value: *val,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    output.push(author_utxo.1.clone());
+    if change > 0 {
+        output.push(TxOut {
+            script_pubkey: change_script,
+            value: change,
+        });
+    }
+    output.push(op_return_output(op_return));
+
+    let tx = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: inputs.iter().map(|i| unsigned_input((i.0).0)).collect(),
+        output,
+    };
+    let (utxos, signers): (Vec<_>, Vec<_>) = inputs.into_iter().unzip();
+    let prevouts: Vec<_> = utxos.into_iter().map(|u| u.1).collect();
+    sign_and_encode(tx, &signers, &prevouts, Some(author))
+}
+
+/// Spends every output of `inputs` paying to `signer` into a single output
+/// paying `to`, with a fee of `fee_rate` per virtual byte.
+pub fn sweep<S: Signer>(
+    signer: &S,
+    inputs: &[Vec<u8>],
+    to: bitcoin::Script,
+    fee_rate: u64,
+) -> Result<Vec<u8>, Error> {
+    use bitcoin::{Transaction, TxOut};
+
+    signer.secret_key()?;
+    let (outpoints, prevouts): (Vec<_>, Vec<_>) =
+        find_utxos(inputs, &signer.scripts())?.into_iter().unzip();
+    ensure!(!prevouts.is_empty(), Funds, "no outputs to sweep");
+
+    let compressed = signer.public_key().len() == 33;
+    let fee = estimate_vsize(&prevouts, 1, compressed) * fee_rate;
+    let total = prevouts.iter().fold(0, |acc, o| acc + o.value);
+    if total <= fee {
+        bail!(
+            Funds,
+            "insufficient funds: {} available, fee is {}",
+            total,
+            fee
+        )
+    }
+
+    let signers: Vec<&dyn Signer> = vec![signer; prevouts.len()];
+    let tx = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: outpoints.into_iter().map(unsigned_input).collect(),
+        output: vec![TxOut {
+            script_pubkey: to,
+            value: total - fee,
+        }],
+    };
+    sign_and_encode(tx, &signers, &prevouts, None)
+}
+
+/// Raw transactions whose outputs pay the wallet at `path`.
+#[derive(Clone, Debug)]
+pub struct PathInputs {
+    pub path: DerivationPath,
+    pub txs: Vec<Vec<u8>>,
+}
+
+/// Merges inputs by path, with `author` first.
+pub(super) fn group_inputs<'a>(
+    author: &'a DerivationPath,
+    inputs: &'a [PathInputs],
+) -> Vec<(&'a DerivationPath, Vec<Vec<u8>>)> {
+    let mut res: Vec<(&DerivationPath, Vec<Vec<u8>>)> = vec![(author, Vec::new())];
+    for i in inputs {
+        match res.iter_mut().find(|(p, _)| *p == &i.path) {
+            Some((_, txs)) => txs.extend(i.txs.iter().cloned()),
+            None => res.push((&i.path, i.txs.clone())),
+        }
+    }
+    res
+}
+
+impl Wallet {
+    /// Signs a transaction spending outputs held by several derived
+    /// addresses. The bork is attributed to `author`, which receives the
+    /// change and, when posting a bork, must have funds among `inputs`.
+    pub fn construct_signed(
+        &mut self,
+        author: &DerivationPath,
+        inputs: &[PathInputs],
+        outputs: &[(&str, u64)],
+        fee: u64,
+        op_return: Option<&[u8]>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut keys: Vec<(KeyWallet, Vec<Vec<u8>>)> = Vec::new();
+        for (path, txs) in group_inputs(author, inputs) {
+            let child = self.parent_mut().derive(path)?;
+            keys.push((KeyWallet::new(child.secret_key()?.clone(), true), txs));
+        }
+
+        let signers: Vec<(&dyn Signer, &[Vec<u8>])> = keys
+            .iter()
+            .map(|(k, txs)| (k as &dyn Signer, txs.as_slice()))
+            .collect();
+        construct_signed_multi(&signers, outputs, fee, op_return)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{self, BorkMessage, NoPrevouts};
+    use crate::{Chain, KeyWallet};
+    use bitcoin::blockdata::script::Instruction;
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin::{Transaction, TxOut};
+
+    fn wallet(byte: u8) -> KeyWallet {
+        KeyWallet::new(secp256k1::SecretKey::parse(&[byte; 32]).unwrap(), true)
+    }
+
+    fn author() -> KeyWallet {
+        wallet(1)
+    }
+
+    fn funding(output: Vec<TxOut>) -> Vec<u8> {
+        serialize(&Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output,
+        })
+    }
+
+    #[test]
+    fn bork_is_authored_by_a_p2pkh_input() {
+        let author = author();
+        let inputs = [funding(vec![
+            TxOut {
+                value: 50_000,
+                script_pubkey: author.witness_script().unwrap(),
+            },
+            TxOut {
+                value: 1000,
+                script_pubkey: author.script(),
+            },
+        ])];
+        let tx = construct_signed(&author, &inputs, &[], 1000, Some(b"bork")).unwrap();
+        let tx: Transaction = deserialize(&tx).unwrap();
+        assert_eq!(tx.input.len(), 2);
+        assert!(tx.input[0].witness.is_empty());
+        assert_eq!(
+            tx_author(&tx, Network::Bitcoin, &mut NoPrevouts),
+            Some(author.address(Network::Bitcoin))
+        );
+    }
+
+    fn comment() -> Vec<u8> {
+        let message = BorkMessage::Comment {
+            nonce: 0,
+            reference_id: vec![0xab; 2],
+            content: "yes".to_owned(),
+        };
+        protocol::encode(&message).unwrap().remove(0)
+    }
+
+    fn authored(outputs: &[(&str, u64)], op_return: &[u8]) -> Result<Transaction, Error> {
+        let (author, funder) = (author(), wallet(2));
+        let author_inputs = [funding(vec![TxOut {
+            value: 1000,
+            script_pubkey: author.script(),
+        }])];
+        let funder_inputs = [funding(vec![TxOut {
+            value: 50_000,
+            script_pubkey: funder.witness_script().unwrap(),
+        }])];
+        let funders: [(&dyn Signer, &[Vec<u8>]); 1] = [(&funder, &funder_inputs)];
+        let tx = construct_authored(&author, &author_inputs, &funders, outputs, 1000, op_return)?;
+        Ok(deserialize(&tx).unwrap())
+    }
+
+    #[test]
+    fn funder_change_is_not_the_recipient() {
+        let to = wallet(3).address(Network::Bitcoin);
+        let tx = authored(&[(&to, 1000)], &comment()).unwrap();
+        assert!(tx
+            .output
+            .iter()
+            .any(|o| o.script_pubkey == wallet(2).script()));
+        let mut prevouts = HashMap::new();
+        prevouts.insert(
+            tx.input[0].previous_output,
+            TxOut {
+                value: 1000,
+                script_pubkey: author().script(),
+            },
+        );
+        let bork = protocol::diagnose_tx(&tx, chrono::Utc::now(), Network::Bitcoin, &mut prevouts)
+            .unwrap()
+            .unwrap();
+        assert_eq!(bork.sender_address, author().address(Network::Bitcoin));
+        assert_eq!(bork.recipient_address, Some(to));
+        assert!(bork.mentions.is_empty());
+    }
+
+    #[test]
+    fn authored_comment_needs_a_recipient() {
+        match authored(&[], &comment()) {
+            Err(Error::Encode(_)) => (),
+            other => panic!("expected an encode error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn segwit_only_author_is_credited_given_the_prevout() {
+        let author = author();
+        let prevout = TxOut {
+            value: 50_000,
+            script_pubkey: author.witness_script().unwrap(),
+        };
+        let inputs = [funding(vec![prevout.clone()])];
+        let tx = construct_signed(&author, &inputs, &[], 1000, Some(b"bork")).unwrap();
+        let tx: Transaction = deserialize(&tx).unwrap();
+        assert!(!tx.input[0].witness.is_empty());
+        assert_eq!(tx_author(&tx, Network::Bitcoin, &mut NoPrevouts), None);
+
+        let outpoint = tx.input[0].previous_output;
+        let mut prevouts = HashMap::new();
+        prevouts.insert(outpoint, prevout.clone());
+        assert_eq!(
+            tx_author(&tx, Network::Bitcoin, &mut prevouts),
+            Some(author.address(Network::Bitcoin))
+        );
+
+        let mut wrong_amount = |o: &bitcoin::OutPoint| {
+            Some(TxOut {
+                value: 40_000,
+                script_pubkey: prevout.script_pubkey.clone(),
+            })
+            .filter(|_| o == &outpoint)
+        };
+        assert_eq!(tx_author(&tx, Network::Bitcoin, &mut wrong_amount), None);
+    }
+
+    #[test]
+    fn segwit_author_can_be_funded_by_another_key() {
+        let (author, funder) = (author(), wallet(2));
+        let author_inputs = [funding(vec![TxOut {
+            value: 1000,
+            script_pubkey: author.witness_script().unwrap(),
+        }])];
+        let funder_inputs = [funding(vec![TxOut {
+            value: 50_000,
+            script_pubkey: funder.script(),
+        }])];
+        let funders: [(&dyn Signer, &[Vec<u8>]); 1] = [(&funder, &funder_inputs)];
+        let tx = construct_authored(&author, &author_inputs, &funders, &[], 1000, b"bork").unwrap();
+        let tx: Transaction = deserialize(&tx).unwrap();
+        let prevouts: Vec<TxOut> = find_utxos(&author_inputs, &author.scripts())
+            .unwrap()
+            .into_iter()
+            .chain(find_utxos(&funder_inputs, &[funder.script()]).unwrap())
+            .map(|u| u.1)
+            .collect();
+        assert!(check_author(&tx, &author, &prevouts).is_ok());
+    }
+
+    #[test]
+    fn bork_needs_author_funds() {
+        let (author, other) = (author(), wallet(2));
+        let inputs = [funding(vec![TxOut {
+            value: 50_000,
+            script_pubkey: other.script(),
+        }])];
+        match construct_signed(&author, &inputs, &[], 1000, Some(b"bork")) {
+            Err(Error::Funds(_)) => (),
+            other => panic!("expected a funds error, got {:?}", other),
+        }
+    }
+
+    /// Whether input `i` of `tx` is a P2PKH spend validly signed by `key`.
+    fn signed_by(tx: &Transaction, i: usize, key: &KeyWallet) -> bool {
+        use bitcoin_hashes::Hash;
+
+        let pushes: Vec<_> = tx.input[i].script_sig.iter(true).collect();
+        let (sig, pubkey) = match pushes.as_slice() {
+            [Instruction::PushBytes(sig), Instruction::PushBytes(pubkey)] => (sig, pubkey),
+            _ => return false,
+        };
+        let (sighash_type, der) = sig.split_last().unwrap();
+        let sighash = tx.signature_hash(i, &key.script(), *sighash_type as u32);
+        let msg = secp256k1::Message::parse(&sighash.into_inner());
+        let sig = secp256k1::Signature::parse_der(der).unwrap();
+        let pk = secp256k1::PublicKey::parse_slice(pubkey, None).unwrap();
+        *pubkey == &key.public_key()[..] && secp256k1::verify(&msg, &sig, &pk)
+    }
+
+    #[test]
+    fn wallet_signs_each_input_with_its_path_key() {
+        let mut wallet = Wallet::from_entropy([0x5a; 16]).unwrap();
+        let paths: Vec<_> = (0..2)
+            .map(|i| DerivationPath::bip44(Network::Bitcoin, 0, Chain::External, i))
+            .collect();
+        let keys: Vec<_> = paths
+            .iter()
+            .map(|p| {
+                let child = wallet.parent_mut().derive(p).unwrap();
+                KeyWallet::new(child.secret_key().unwrap().clone(), true)
+            })
+            .collect();
+        let inputs: Vec<_> = keys
+            .iter()
+            .zip(&paths)
+            .map(|(key, path)| PathInputs {
+                path: path.clone(),
+                txs: vec![funding(vec![TxOut {
+                    value: 1000,
+                    script_pubkey: key.script(),
+                }])],
+            })
+            .collect();
+
+        // The other path is listed first, and the fee needs both inputs.
+        let inputs = [inputs[1].clone(), inputs[0].clone()];
+        let tx = wallet
+            .construct_signed(&paths[0], &inputs, &[], 1500, Some(b"bork"))
+            .unwrap();
+        let tx: Transaction = deserialize(&tx).unwrap();
+        assert_eq!(tx.input.len(), 2);
+        let author_funding: Transaction = deserialize(&inputs[1].txs[0]).unwrap();
+        assert_eq!(tx.input[0].previous_output.txid, author_funding.txid());
+        assert!(signed_by(&tx, 0, &keys[0]));
+        assert!(signed_by(&tx, 1, &keys[1]));
+        assert!(!signed_by(&tx, 1, &keys[0]));
+        assert_eq!(
+            tx_author(&tx, Network::Bitcoin, &mut NoPrevouts),
+            Some(keys[0].address(Network::Bitcoin))
+        );
+    }
+}